@@ -0,0 +1,532 @@
+use std::time::Instant;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::{mpsc, oneshot},
+    time,
+};
+
+use crate::{
+    options::{ConnectOptions, TransportKind},
+    protocol::Protocol,
+    store::SessionStore,
+    types::QOS,
+};
+
+pub use crate::protocol::Event;
+
+///How many requests may wait for the event loop before the client handles wait in turn.
+const REQUEST_CAPACITY: usize = 64;
+
+///Represents a request from a client handle, with the channel its result is sent back on.
+#[derive(Debug)]
+struct Request {
+    command: Command,
+    reply: oneshot::Sender<crate::types::error::Result<i32>>,
+}
+
+///Represents what a client handle asked the event loop to do.
+#[derive(Debug)]
+enum Command {
+    Publish {
+        topic: String,
+        payload: Vec<u8>,
+        qos: QOS,
+        retain: bool,
+    },
+    Subscribe {
+        topic: String,
+        qos: QOS,
+    },
+    Unsubscribe {
+        topic: String,
+    },
+    Disconnect,
+}
+
+///Represents a handle to an async MQTT client. Handles are cheap to clone and can be moved across tasks.
+///
+///Requests are carried out by the `EventLoop`, which must be polled for them to complete.
+#[derive(Debug, Clone)]
+pub struct AsyncClient {
+    requests: mpsc::Sender<Request>,
+}
+
+impl AsyncClient {
+    ///Sends a request to the event loop and waits for its result.
+    async fn request(&self, command: Command) -> crate::types::error::Result<i32> {
+        let (reply, result) = oneshot::channel();
+        self.requests
+            .send(Request { command, reply })
+            .await
+            .map_err(|_| crate::types::error::Error::RequestError)?;
+        result
+            .await
+            .map_err(|_| crate::types::error::Error::RequestError)?
+    }
+    ///Publishes a message to a topic with a specified QoS and retain flag.
+    ///Returns the packet id of the publication, or 0 for QoS 0 which carries none.
+    pub async fn publish(
+        &self,
+        topic: &str,
+        message_text: &str,
+        qos: QOS,
+        retain: bool,
+    ) -> crate::types::error::Result<i32> {
+        self.request(Command::Publish {
+            topic: topic.to_owned(),
+            payload: message_text.as_bytes().to_vec(),
+            qos,
+            retain,
+        })
+        .await
+    }
+    ///Subscribes to a topic with a specified QoS. Returns the packet id of the SUBSCRIBE.
+    pub async fn subscribe(&self, topic: &str, qos: QOS) -> crate::types::error::Result<i32> {
+        self.request(Command::Subscribe {
+            topic: topic.to_owned(),
+            qos,
+        })
+        .await
+    }
+    ///Unsubscribes from a topic. Returns the packet id of the UNSUBSCRIBE.
+    pub async fn unsubscribe(&self, topic: &str) -> crate::types::error::Result<i32> {
+        self.request(Command::Unsubscribe {
+            topic: topic.to_owned(),
+        })
+        .await
+    }
+    ///Disconnects from the server. The event loop reports `Event::Disconnected` and stops.
+    pub async fn disconnect(&self) -> crate::types::error::Result<()> {
+        self.request(Command::Disconnect).await.map(|_| ())
+    }
+}
+
+///Represents the event loop of an async client, driving its I/O and keep-alive.
+///It connects over plain TCP only, without a proxy.
+///
+///`poll` must be called in a loop. After it returns an error the connection is lost,
+///and the next call reconnects following the reconnect policy.
+#[derive(Debug)]
+pub struct EventLoop {
+    options: ConnectOptions,
+    stream: Option<TcpStream>,
+    requests: mpsc::Receiver<Request>,
+    requests_closed: bool,
+    protocol: Protocol,
+    store: Option<Box<dyn SessionStore>>,
+    attempt: u32,
+    intent_disconnect: bool,
+}
+
+///Represents what woke the event loop up.
+enum Wakeup {
+    Request(Option<Request>),
+    Read(std::io::Result<usize>),
+    KeepAlive,
+}
+
+impl EventLoop {
+    ///Creates a new EventLoop instance with its client handle, connecting to the server and waiting for its CONNACK.
+    ///
+    ///Unless the session is clean, the session state is restored from the session store first.
+    pub(crate) async fn connect(
+        mut options: ConnectOptions,
+    ) -> crate::types::error::Result<(AsyncClient, Self)> {
+        options.validate()?;
+        let (requests_sender, requests) = mpsc::channel(REQUEST_CAPACITY);
+        let mut store = options.session_store.take();
+        let offline_queue = options.offline_queue.take();
+        let mut protocol = Protocol::new(&options, offline_queue);
+        if let (false, Some(store)) = (options.clean_session, store.as_mut()) {
+            protocol.restore_session(store.load()?);
+        }
+        let mut event_loop = Self {
+            options,
+            stream: None,
+            requests,
+            requests_closed: false,
+            protocol,
+            store,
+            attempt: 0,
+            intent_disconnect: false,
+        };
+        event_loop.open().await?;
+        let client = AsyncClient {
+            requests: requests_sender,
+        };
+        Ok((client, event_loop))
+    }
+    ///Waits for the next event, handling requests from the client handles, incoming packets and keep-alive meanwhile.
+    pub async fn poll(&mut self) -> crate::types::error::Result<Event> {
+        loop {
+            if let Some(event) = self.protocol.poll_event() {
+                return Ok(event);
+            }
+            if self.stream.is_none() {
+                if self.intent_disconnect {
+                    return Err(crate::types::error::Error::ConnectionError);
+                }
+                self.reconnect().await?;
+                continue;
+            }
+            if let Err(e) = self.step().await {
+                self.connection_lost();
+                return Err(e);
+            }
+        }
+    }
+    ///Waits for a request, incoming bytes or the keep-alive timer, and handles whichever comes first.
+    async fn step(&mut self) -> crate::types::error::Result<()> {
+        let timeout = self.protocol.timeout(Instant::now());
+        let Some(stream) = self.stream.as_mut() else {
+            return Err(crate::types::error::Error::ConnectionError);
+        };
+        let mut buf = [0_u8; 1024];
+        let wakeup = tokio::select! {
+            request = self.requests.recv(), if !self.requests_closed => Wakeup::Request(request),
+            res = stream.read(&mut buf) => Wakeup::Read(res),
+            _ = sleep(timeout) => Wakeup::KeepAlive,
+        };
+        match wakeup {
+            Wakeup::Request(Some(request)) => {
+                let res = self.handle(request.command).await;
+                let _ = request.reply.send(res);
+                match self.stream {
+                    None if !self.intent_disconnect => Err(crate::types::error::Error::ConnectionError),
+                    _ => Ok(()),
+                }
+            }
+            Wakeup::Request(None) => {
+                self.requests_closed = true;
+                Ok(())
+            }
+            Wakeup::Read(Ok(n)) if n > 0 => {
+                let res = self.protocol.handle_incoming(&buf[..n]);
+                self.persist()?;
+                res?;
+                self.flush().await
+            }
+            Wakeup::Read(_) => Err(crate::types::error::Error::ConnectionError),
+            Wakeup::KeepAlive => {
+                self.protocol.handle_timeout(Instant::now())?;
+                self.flush().await
+            }
+        }
+    }
+    ///Reconnects following the reconnect policy, failing once the allowed attempts are used up.
+    ///
+    ///Requests keep being handled while waiting out the delay: publications go to the offline queue,
+    ///and a disconnect stops reconnecting.
+    async fn reconnect(&mut self) -> crate::types::error::Result<()> {
+        self.attempt += 1;
+        if !self.options.reconnect_policy.allows(self.attempt) {
+            return Err(crate::types::error::Error::ConnectionError);
+        }
+        let delay = time::sleep(self.options.reconnect_policy.delay(self.attempt));
+        tokio::pin!(delay);
+        loop {
+            let request = tokio::select! {
+                request = self.requests.recv(), if !self.requests_closed => request,
+                _ = &mut delay => break,
+            };
+            match request {
+                Some(Request {
+                    command: Command::Disconnect,
+                    reply,
+                }) => {
+                    self.intent_disconnect = true;
+                    let _ = reply.send(Ok(0));
+                    return Err(crate::types::error::Error::ConnectionError);
+                }
+                Some(request) => {
+                    let res = self.handle(request.command).await;
+                    let _ = request.reply.send(res);
+                }
+                None => self.requests_closed = true,
+            }
+        }
+        self.open().await
+    }
+    ///Opens a TCP stream to the server, sends CONNECT and waits for the CONNACK.
+    async fn open(&mut self) -> crate::types::error::Result<()> {
+        if self.options.transport() != &TransportKind::Tcp || self.options.proxy.is_some() {
+            return Err(crate::types::error::Error::UnsupportedTransportError);
+        }
+        let connect_timeout = self.options.connect_timeout;
+        let address = crate::uri::authority(self.options.host(), self.options.port());
+        let stream = time::timeout(connect_timeout, TcpStream::connect(address))
+            .await
+            .map_err(|_| crate::types::error::Error::TimeoutError)?
+            .map_err(|_| crate::types::error::Error::ConnectionError)?;
+        self.stream = Some(stream);
+        self.protocol.connect(Instant::now())?;
+        let res = match time::timeout(connect_timeout, self.handshake()).await {
+            Ok(res) => res,
+            Err(_) => Err(crate::types::error::Error::TimeoutError),
+        };
+        if let Err(e) = res {
+            self.connection_lost();
+            return Err(e);
+        }
+        self.attempt = 0;
+        self.persist()?;
+        self.flush().await
+    }
+    ///Writes CONNECT and reads from the server until the protocol has handled the CONNACK.
+    async fn handshake(&mut self) -> crate::types::error::Result<()> {
+        self.flush().await?;
+        let mut buf = [0_u8; 1024];
+        while !self.protocol.is_connected() {
+            let Some(stream) = self.stream.as_mut() else {
+                return Err(crate::types::error::Error::ConnectionError);
+            };
+            match stream.read(&mut buf).await {
+                Ok(n) if n > 0 => self.protocol.handle_incoming(&buf[..n])?,
+                _ => return Err(crate::types::error::Error::ConnectionError),
+            }
+        }
+        Ok(())
+    }
+    ///Drops the stream and tells the protocol the connection is gone.
+    fn connection_lost(&mut self) {
+        self.stream = None;
+        self.protocol.connection_lost();
+    }
+    ///Saves the session state to the session store, if one is set and the state changed.
+    fn persist(&mut self) -> crate::types::error::Result<()> {
+        match self.store.as_mut() {
+            Some(store) if self.protocol.session_changed() => store.save(self.protocol.session()),
+            _ => Ok(()),
+        }
+    }
+    ///Writes the bytes the protocol has to send, treating the connection as lost if that fails.
+    async fn flush(&mut self) -> crate::types::error::Result<()> {
+        match self.protocol.poll_transmit(Instant::now()) {
+            Some(bytes) => self.write(&bytes).await,
+            None => Ok(()),
+        }
+    }
+    ///Writes bytes to the server, treating the connection as lost if that fails.
+    async fn write(&mut self, bytes: &[u8]) -> crate::types::error::Result<()> {
+        let Some(stream) = self.stream.as_mut() else {
+            return Err(crate::types::error::Error::RequestError);
+        };
+        if stream.write_all(bytes).await.is_err() {
+            self.connection_lost();
+            return Err(crate::types::error::Error::RequestError);
+        }
+        Ok(())
+    }
+    ///Carries out a request from a client handle, returning the packet id it used.
+    ///
+    ///If writing a publication fails the connection is treated as lost: QoS 1 and QoS 2 publications stay in the session
+    ///and are sent again once a reconnect resumes it, while QoS 0 publications are queued or fail.
+    async fn handle(&mut self, command: Command) -> crate::types::error::Result<i32> {
+        match command {
+            Command::Publish {
+                topic,
+                payload,
+                qos,
+                retain,
+            } => {
+                let pid = self.protocol.publish(&topic, &payload, qos, retain)?;
+                let Some(bytes) = self.protocol.poll_transmit(Instant::now()) else {
+                    return Ok(pid);
+                };
+                if let Err(e) = self.persist() {
+                    if let Ok(pid @ 1..) = u16::try_from(pid) {
+                        self.protocol.discard(pid);
+                    }
+                    let _ = self.persist();
+                    return Err(e);
+                }
+                if self.write(&bytes).await.is_err() && qos == QOS::Zero {
+                    return self.protocol.publish(&topic, &payload, qos, retain);
+                }
+                Ok(pid)
+            }
+            Command::Subscribe { topic, qos } => {
+                let pid = self.protocol.subscribe(&topic, qos)?;
+                self.persist()?;
+                self.flush().await?;
+                Ok(pid.into())
+            }
+            Command::Unsubscribe { topic } => {
+                let pid = self.protocol.unsubscribe(&topic)?;
+                self.persist()?;
+                self.flush().await?;
+                Ok(pid.into())
+            }
+            Command::Disconnect => {
+                self.protocol.disconnect()?;
+                self.flush().await?;
+                self.intent_disconnect = true;
+                if let Some(mut stream) = self.stream.take() {
+                    let _ = stream.shutdown().await;
+                }
+                Ok(0)
+            }
+        }
+    }
+}
+
+///Sleeps for the given duration, or forever if there is none.
+async fn sleep(timeout: Option<std::time::Duration>) {
+    match timeout {
+        Some(timeout) => time::sleep(timeout).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::{
+        offline_queue::{OfflineQueue, OverflowPolicy},
+        reconnect::ReconnectPolicy,
+        types::{header, payload, Acknowledgement, ControlPacket, Integer},
+    };
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    ///Represents the broker side of a connection.
+    struct Peer {
+        stream: TcpStream,
+        bytes: VecDeque<u8>,
+    }
+
+    impl Peer {
+        async fn accept(listener: &TcpListener) -> Self {
+            let (stream, _) = listener.accept().await.unwrap();
+            Self {
+                stream,
+                bytes: VecDeque::new(),
+            }
+        }
+        async fn read_packet(&mut self) -> ControlPacket {
+            loop {
+                if let Some(packet) = ControlPacket::from_bytes(&mut self.bytes).unwrap() {
+                    return packet;
+                }
+                let mut buf = [0_u8; 256];
+                let n = self.stream.read(&mut buf).await.unwrap();
+                assert!(n > 0, "connection closed");
+                self.bytes.extend(&buf[..n]);
+            }
+        }
+        async fn write(&mut self, bytes: &[u8]) {
+            self.stream.write_all(bytes).await.unwrap();
+        }
+    }
+
+    async fn listen() -> (TcpListener, u16) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, port)
+    }
+
+    #[tokio::test]
+    async fn async_client_test() {
+        let (listener, port) = listen().await;
+        let broker = tokio::spawn(async move {
+            let mut peer = Peer::accept(&listener).await;
+            assert_eq!(peer.read_packet().await.header.fixed(), header::FixedHeader::Connect);
+            peer.write(&[0x20, 0x02, 0x00, 0x00]).await;
+            assert_eq!(peer.read_packet().await.header.fixed(), header::FixedHeader::Subscribe);
+            peer.write(&[0x90, 0x03, 0x00, 0x01, 0x01]).await;
+            peer.write(&[0x32, 0x09, 0x00, 0x03, b'a', b'/', b'b', 0x00, 0x07, b'h', b'i']).await;
+            let ack = peer.read_packet().await;
+            assert_eq!(ack, ControlPacket::acknowledgement(Acknowledgement::Puback, Integer::new(7)));
+            let publish = peer.read_packet().await;
+            assert_eq!(publish.header.fixed(), header::FixedHeader::Publish(false, QOS::One, false));
+            peer.write(&[0x40, 0x02, 0x00, 0x02]).await;
+            assert_eq!(peer.read_packet().await.header.fixed(), header::FixedHeader::Disconnect);
+        });
+        let (client, mut event_loop) = ConnectOptions::new(&format!("mqtt://127.0.0.1:{port}?client_id=id"))
+            .unwrap()
+            .connect_async()
+            .await
+            .unwrap();
+        assert_eq!(event_loop.poll().await, Ok(Event::Connected(false)));
+        let handle = client.clone();
+        let subscribe = tokio::spawn(async move { handle.subscribe("a/#", QOS::One).await });
+        assert_eq!(event_loop.poll().await, Ok(Event::Subscribed(1)));
+        assert_eq!(subscribe.await.unwrap(), Ok(1));
+        let Ok(Event::Message(msg)) = event_loop.poll().await else {
+            panic!("expected a message");
+        };
+        assert_eq!((msg.topic(), msg.payload_str()), ("a/b", Some("hi")));
+        let handle = client.clone();
+        let publish = tokio::spawn(async move { handle.publish("a/c", "hello", QOS::One, false).await });
+        assert_eq!(event_loop.poll().await, Ok(Event::Published(2)));
+        assert_eq!(publish.await.unwrap(), Ok(2));
+        let disconnect = tokio::spawn(async move { client.disconnect().await });
+        assert_eq!(event_loop.poll().await, Ok(Event::Disconnected));
+        assert_eq!(disconnect.await.unwrap(), Ok(()));
+        assert_eq!(event_loop.poll().await, Err(crate::types::error::Error::ConnectionError));
+        broker.await.unwrap();
+    }
+    #[tokio::test]
+    async fn async_client_test1() {
+        let (listener, port) = listen().await;
+        let broker = tokio::spawn(async move {
+            let mut peer = Peer::accept(&listener).await;
+            peer.read_packet().await;
+            peer.write(&[0x20, 0x02, 0x00, 0x00]).await;
+            assert_eq!(peer.read_packet().await.header.fixed(), header::FixedHeader::Pingreq);
+            peer.write(&[0xd0, 0x00]).await;
+        });
+        let (_client, mut event_loop) = ConnectOptions::new(&format!("mqtt://127.0.0.1:{port}?keep_alive=1"))
+            .unwrap()
+            .connect_async()
+            .await
+            .unwrap();
+        assert_eq!(event_loop.poll().await, Ok(Event::Connected(false)));
+        assert_eq!(event_loop.poll().await, Err(crate::types::error::Error::ConnectionError));
+        broker.await.unwrap();
+    }
+    #[tokio::test]
+    async fn async_client_test2() {
+        let res = ConnectOptions::new("mqtts://127.0.0.1:1").unwrap().connect_async().await;
+        assert_eq!(res.err(), Some(crate::types::error::Error::UnsupportedTransportError));
+    }
+    #[tokio::test]
+    async fn async_client_test3() {
+        let (listener, port) = listen().await;
+        let broker = tokio::spawn(async move {
+            let mut peer = Peer::accept(&listener).await;
+            peer.read_packet().await;
+            peer.write(&[0x20, 0x02, 0x00, 0x00]).await;
+            drop(peer);
+            drop(Peer::accept(&listener).await);
+            let mut peer = Peer::accept(&listener).await;
+            assert_eq!(peer.read_packet().await.header.fixed(), header::FixedHeader::Connect);
+            peer.write(&[0x20, 0x02, 0x00, 0x00]).await;
+            peer.read_packet().await
+        });
+        let (client, mut event_loop) = ConnectOptions::new(&format!("mqtt://127.0.0.1:{port}"))
+            .unwrap()
+            .reconnect_policy(ReconnectPolicy {
+                initial_delay: Duration::from_secs(1),
+                jitter: 0.0,
+                ..Default::default()
+            })
+            .offline_queue(OfflineQueue::new(4, 1024, OverflowPolicy::Reject))
+            .connect_async()
+            .await
+            .unwrap();
+        assert_eq!(event_loop.poll().await, Ok(Event::Connected(false)));
+        assert_eq!(event_loop.poll().await, Err(crate::types::error::Error::ConnectionError));
+        assert_eq!(event_loop.poll().await, Err(crate::types::error::Error::ConnectionError));
+        let publish = tokio::spawn(async move { client.publish("a", "queued", QOS::One, false).await });
+        let (event, publish) = tokio::join!(event_loop.poll(), time::timeout(Duration::from_millis(500), publish));
+        assert_eq!(publish.unwrap().unwrap(), Ok(1));
+        assert_eq!(event, Ok(Event::Connected(false)));
+        let publish = broker.await.unwrap();
+        assert_eq!(publish.payload.content, Some(payload::Payloads::Publish(b"queued".to_vec())));
+    }
+}
@@ -1,40 +1,63 @@
 use std::{
-    io::{Read, Write},
-    net::TcpStream,
-    sync::Arc,
-    time::Instant,
+    io::ErrorKind,
+    sync::{Arc, Condvar, Mutex, RwLock},
+    thread,
+    time::{Duration, Instant},
 };
 
 use crate::{
+    options::ConnectOptions,
+    protocol::{Event, Protocol},
+    store::SessionStore,
     topic_matcher::TopicMatcher,
+    transport::{self, Transport},
     types::{
-        header::{self, Header, VariableHeader},
-        payload::{self, ConnectPayload, Payload, SubscribePayload},
-        CallbackFunc, ControlPacket, EncodedString, Integer, LogCollbackFunc,
-        ServerConnection, Will, QOS,
+        payload::SubscribePayload,
+        message::Message,
+        CallbackFunc, LogCollbackFunc, QOS,
     },
 };
 
-///Represents an MQTT client, with fields for client ID, server connection, clean session, will, TCP stream, and intent to disconnect.
+///How often the client loop checks whether it was asked to stop while waiting for the server.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+///Represents an MQTT client, with fields for connect options, transport, intent of the application,
+///the protocol state machine, session store and background network thread.
+///
+///A client is created by `ConnectOptions::connect`.
 #[derive(Debug)]
 pub struct Client {
-    client_id: String,
-    server_connection: ServerConnection,
-    clean_session: bool,
-    will: Option<Will>,
-    tcp_stream: Arc<TcpStream>,
-    intent_disconnect: bool,
+    options: ConnectOptions,
+    transport: RwLock<Arc<dyn Transport>>,
+    intent: Mutex<Intent>,
+    intent_changed: Condvar,
+    protocol: Mutex<Protocol>,
+    store: Mutex<Option<Box<dyn SessionStore>>>,
+    network_thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+///Represents what the application asked of the client loop.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Intent {
+    ///Keep running, reconnecting when the connection is lost.
+    #[default]
+    Run,
+    ///Return from the client loop, leaving the connection open.
+    StopLoop,
+    ///Disconnect on purpose.
+    Disconnect,
 }
 
 ///Represents a set of callbacks for the client.
 pub struct Callbacks<'a, T> {
     pub data: T,
-    message_callback: CallbackFunc<'a, T, ControlPacket>,
+    message_callback: CallbackFunc<'a, T, Message>,
     connect_callback: CallbackFunc<'a, T, i32>,
     publish_callback: CallbackFunc<'a, T, i32>,
     subscribe_callback: CallbackFunc<'a, T, i32>,
     unsubscribe_callback: CallbackFunc<'a, T, i32>,
     disconnect_callback: CallbackFunc<'a, T, i32>,
+    reconnecting_callback: CallbackFunc<'a, T, u32>,
     log_callback: LogCollbackFunc<'a, T>,
 }
 
@@ -49,11 +72,12 @@ impl<'a, T> Callbacks<'a, T> {
             subscribe_callback: None,
             unsubscribe_callback: None,
             disconnect_callback: None,
+            reconnecting_callback: None,
             log_callback: None,
         }
     }
     ///Sets the message callback.
-    pub fn on_message<C: Fn(&mut T, ControlPacket) + 'a>(&mut self, callback: C) {
+    pub fn on_message<C: Fn(&mut T, Message) + 'a>(&mut self, callback: C) {
         self.message_callback = Some(Box::new(callback));
     }
     ///Sets the connect callback.
@@ -76,6 +100,10 @@ impl<'a, T> Callbacks<'a, T> {
     pub fn on_disconnect<C: Fn(&mut T, i32) + 'a>(&mut self, callback: C) {
         self.disconnect_callback = Some(Box::new(callback));
     }
+    ///Sets the reconnecting callback, called with the attempt number before each reconnect attempt.
+    pub fn on_reconnecting<C: Fn(&mut T, u32) + 'a>(&mut self, callback: C) {
+        self.reconnecting_callback = Some(Box::new(callback));
+    }
     ///Sets the log callback.
     pub fn on_log<C: Fn(&mut T, u32, &str) + 'a>(&mut self, callback: C) {
         self.log_callback = Some(Box::new(callback));
@@ -85,82 +113,99 @@ impl<'a, T> Callbacks<'a, T> {
 impl Client {
     ///Returns the host of the server connection.
     pub fn host(&self) -> &str {
-        &self.server_connection.host
+        self.options.host()
     }
     ///Returns the port of the server connection.
     pub fn port(&self) -> u32 {
-        self.server_connection.port
+        self.options.port()
+    }
+    ///Returns the current transport.
+    fn stream(&self) -> Arc<dyn Transport> {
+        self.transport
+            .read()
+            .expect("FATAL: stream lock poisoned")
+            .clone()
+    }
+    ///Locks the protocol state machine.
+    fn protocol(&self) -> std::sync::MutexGuard<'_, Protocol> {
+        lock(&self.protocol)
+    }
+    ///Returns true if the server resumed a stored session on the last connect.
+    pub fn session_present(&self) -> bool {
+        self.protocol().session_present()
+    }
+    ///Saves the session state to the session store, if one is set and the state changed.
+    fn persist(&self, protocol: &mut Protocol) -> crate::types::error::Result<()> {
+        if !protocol.session_changed() {
+            return Ok(());
+        }
+        let mut store = self
+            .store
+            .lock()
+            .expect("FATAL: session store lock poisoned");
+        match store.as_mut() {
+            Some(store) => store.save(protocol.session()),
+            None => Ok(()),
+        }
     }
     ///Subscribes to a topic with a specified QoS.
+    ///The subscription becomes active once the server grants it in its SUBACK.
     pub fn subscribe(&self, topic: &'static str, qos: QOS) -> crate::types::error::Result<TopicMatcher> {
-        let pid = Instant::now().elapsed().subsec_millis() as u16;
-        let packet = ControlPacket {
-            header: Header {
-                fixed: header::FixedHeader::Subscribe,
-                variable: Some(VariableHeader::Subscribe(header::Subscribe {
-                    packet_id: Integer::new(pid),
-                })),
-            },
-            payload: Payload {
-                content: Some(payload::Payloads::Subscribe(vec![SubscribePayload {
-                    topic_filter: EncodedString::new(topic),
-                    qos,
-                }])),
-            },
-        };
         let tm = TopicMatcher::new(topic)?;
-        self.tcp_stream
-            .as_ref()
-            .write_all(&packet.to_bytes())
-            .map_err(|_| {
-                crate::types::error::Error::RequestError
-            })?;
+        let mut protocol = self.protocol();
+        protocol.subscribe(topic, qos)?;
+        self.persist(&mut protocol)?;
+        flush(self.stream().as_ref(), &mut protocol)?;
         Ok(tm)
     }
+    ///Returns the active subscriptions, which are replayed after a reconnect that did not resume the session.
+    pub fn subscriptions(&self) -> Vec<SubscribePayload> {
+        self.protocol().subscriptions().to_vec()
+    }
     ///Unsubscribes from a topic.
     pub fn unsubscribe(&self, topic: &'static str) -> crate::types::error::Result<i32> {
-        let pid = Instant::now().elapsed().subsec_millis() as u16;
-        let packet = ControlPacket {
-            header: Header {
-                fixed: header::FixedHeader::Unsubscribe,
-                variable: Some(VariableHeader::Unsubscribe(header::Unsubscribe {
-                    packet_id: Integer::new(pid),
-                })),
-            },
-            payload: Payload {
-                content: Some(payload::Payloads::Unsubscribe(vec![EncodedString::new(
-                    topic,
-                )])),
-            },
-        };
-        self.tcp_stream
-            .as_ref()
-            .write_all(&packet.to_bytes())
-            .map_err(|_| {
-                crate::types::error::Error::RequestError
-            })?;
+        let mut protocol = self.protocol();
+        let pid = protocol.unsubscribe(topic)?;
+        self.persist(&mut protocol)?;
+        flush(self.stream().as_ref(), &mut protocol)?;
         Ok(pid.into())
     }
-    ///Disconnects from the server.
-    pub fn disconnect(&mut self) -> crate::types::error::Result<()>{
-        let packet = ControlPacket {
-            header: Header::new(header::FixedHeader::Disconnect, None),
-            payload: Payload { content: None },
-        };
-        self.intent_disconnect = true;
-        let res = self
-            .tcp_stream
-            .as_ref()
-            .write_all(&packet.to_bytes())
-            .map_err(|_| {
-                crate::types::error::Error::RequestError
-            });
-        if res.is_err() {
-            self.intent_disconnect = false;
-        }
-        res
+    ///Disconnects from the server by sending DISCONNECT and closing the connection.
+    pub fn disconnect(&self) -> crate::types::error::Result<()>{
+        let mut protocol = self.protocol();
+        protocol.disconnect()?;
+        self.set_intent(Intent::Disconnect);
+        let stream = self.stream();
+        flush(stream.as_ref(), &mut protocol)?;
+        let _ = stream.shutdown();
+        Ok(())
+    }
+    ///Locks the intent of the application.
+    fn intent(&self) -> std::sync::MutexGuard<'_, Intent> {
+        self.intent
+            .lock()
+            .expect("FATAL: intent lock poisoned")
+    }
+    ///Records the intent of the application, waking the client loop from a reconnect delay.
+    fn set_intent(&self, intent: Intent) {
+        *self.intent() = intent;
+        self.intent_changed.notify_all();
+    }
+    ///Sleeps for the given delay, waking early if the client loop is asked to stop or the client disconnects on purpose.
+    ///Returns false if it did.
+    fn sleep(&self, delay: Duration) -> bool {
+        let (intent, _) = self
+            .intent_changed
+            .wait_timeout_while(self.intent(), delay, |intent| *intent == Intent::Run)
+            .expect("FATAL: intent lock poisoned");
+        *intent == Intent::Run
     }
     ///Publishes a message to a topic with a specified QoS and retain flag.
+    ///Returns the packet id of the publication, or 0 for QoS 0 which carries none.
+    ///
+    ///While disconnected the publication is queued in the offline queue, and fails if there is none.
+    ///If writing it fails the connection is treated as lost: QoS 1 and QoS 2 publications stay in the session
+    ///and are sent again once a reconnect resumes it, while QoS 0 publications are queued or fail.
     pub fn publish(
         &self,
         topic: &str,
@@ -168,259 +213,906 @@ impl Client {
         qos: QOS,
         retain: bool,
     ) -> crate::types::error::Result<i32> {
-        let pid = Instant::now().elapsed().subsec_millis() as u16;
-        let header = Header::new(
-            header::FixedHeader::Publish(false, qos, retain),
-            Some(VariableHeader::Publish(header::Publish {
-                topic_name: EncodedString::new(topic),
-                packet_id: Integer::new(pid),
-            })),
-        );
-        let payload = Payload {
-            content: Some(payload::Payloads::Publish(message_text.as_bytes().to_vec())),
+        let mut protocol = self.protocol();
+        let pid = protocol.publish(topic, message_text.as_bytes(), qos, retain)?;
+        let Some(bytes) = protocol.poll_transmit(Instant::now()) else {
+            return Ok(pid);
         };
-        let packet = ControlPacket { header, payload };
-        self.tcp_stream
-            .as_ref()
-            .write_all(&packet.to_bytes())
-            .map_err(|_| {
-                crate::types::error::Error::RequestError
-            })?;
-        Ok(pid as i32)
-    }
-    ///Creates a new Client instance.
-    pub fn new(
-        client_id: String,
-        will: Option<Will>,
-        clean_session: bool,
-        host: &str,
-        port: u32,
-        username: Option<String>,
-        pass: Option<String>,
-    ) -> crate::types::error::Result<Self> {
-        let mut tcp_stream = TcpStream::connect(format!("{}:{}", host, port)).map_err(|_| {
-            crate::types::error::Error::ConnectionError
-        })?;
-        let mut flags = 0_u8;
-        if username.is_some() { flags+=2_u8.pow(7)}
-        if pass.is_some() && username.is_some() { flags+=2_u8.pow(6)}
-        if clean_session { flags+=2_u8 }
-        if let Some(will) = &will {
-            let will_qos_flags = match will.qos {
-                QOS::One => [0, 1],
-                QOS::Two => [1, 0],
-                QOS::Zero => [0, 0],
-            };
-            if will.retain { flags+=2_u8.pow(5) }
-            if will_qos_flags[0] == 1 { flags+=2_u8.pow(4) }
-            if will_qos_flags[1] == 1 { flags+=2_u8.pow(3) }
-            flags+=2_u8.pow(2);
+        if let Err(e) = self.persist(&mut protocol) {
+            if let Ok(pid @ 1..) = u16::try_from(pid) {
+                protocol.discard(pid);
+            }
+            let _ = self.persist(&mut protocol);
+            return Err(e);
         }
-
-        let header = Header::new(
-            header::FixedHeader::Connect,
-            Some(VariableHeader::Connect(header::Connect {
-                protocol_name: EncodedString::new("MQTT"),
-                protocol_level: 4_u8,
-                connect_flags: flags,
-                keep_alive: Integer::new(0),
-            })),
-        );
-        let will_payload = will.clone();
-        let payload = Payload {
-            content: Some(payload::Payloads::Connect(ConnectPayload::new(
-                &client_id,
-                will_payload.clone().map(|w| w.topic).as_deref(),
-                will_payload.map(|w| w.message).as_deref(),
-                username.clone(),
-                pass.clone(),
-            ))),
+        let stream = self.stream();
+        if stream.write_all(&bytes).is_err() {
+            protocol.connection_lost();
+            let _ = stream.shutdown();
+            if qos == QOS::Zero {
+                return protocol.publish(topic, message_text.as_bytes(), qos, retain);
+            }
+        }
+        Ok(pid)
+    }
+    ///Creates a new Client instance from the connect options, speaking MQTT over the given connected transport
+    ///or over a new one opened from the options, and waiting for the server's CONNACK.
+    ///
+    ///Unless the session is clean, the session state is restored from the session store first.
+    pub(crate) fn connect(
+        mut options: ConnectOptions,
+        transport: Option<Box<dyn Transport>>,
+    ) -> crate::types::error::Result<Self> {
+        options.validate()?;
+        let transport = match transport {
+            Some(transport) => transport,
+            None => transport::connect(&options)?,
         };
-        let packet = ControlPacket { header, payload };
-        let server_connection = ServerConnection {
-            username: username.as_deref().map(EncodedString::new),
-            password: pass.as_deref().map(EncodedString::new),
-            host: host.to_owned(),
-            port,
+        let mut store = options.session_store.take();
+        let offline_queue = options.offline_queue.take();
+        let mut protocol = Protocol::new(&options, offline_queue);
+        if let (false, Some(store)) = (options.clean_session, store.as_mut()) {
+            protocol.restore_session(store.load()?);
+        }
+        let protocol = Mutex::new(protocol);
+        handshake(transport.as_ref(), &protocol, options.connect_timeout)?;
+        let client = Client {
+            options,
+            transport: RwLock::new(Arc::from(transport)),
+            intent: Mutex::new(Intent::Run),
+            intent_changed: Condvar::new(),
+            protocol,
+            store: Mutex::new(store),
+            network_thread: Mutex::new(None),
         };
-        tcp_stream.write_all(&packet.to_bytes()).map_err(|_| {
-            crate::types::error::Error::RequestError
-        })?;
-        Ok(Client {
-            client_id,
-            clean_session,
-            server_connection,
-            will,
-            tcp_stream: Arc::new(tcp_stream),
-            intent_disconnect: false,
-        })
+        client.persist(&mut client.protocol())?;
+        Ok(client)
     }
 
-    ///Reconnects to the server.
+    ///Reconnects to the server over a fresh transport opened from the connect options and waits for its CONNACK.
+    ///
+    ///If the server resumed the session, unacknowledged PUBLISH and PUBREL packets are sent again in order.
+    ///Otherwise the messages in flight are discarded and the active subscriptions are replayed.
+    ///Publishes queued while disconnected are sent afterwards, in the order they were made.
     pub fn reconnect(&self) -> crate::types::error::Result<()> {
-        let mut flags = 0_u8;
-        if self.server_connection.username.is_some() { flags+=2_u8.pow(7)}
-        if self.server_connection.password.is_some() && self.server_connection.username.is_some() { flags+=2_u8.pow(6)}
-        if self.clean_session { flags+=2_u8 }
-        if let Some(will) = &self.will {
-            let will_qos_flags = match will.qos {
-                QOS::One => [0, 1],
-                QOS::Two => [1, 0],
-                QOS::Zero => [0, 0],
-            };
-            if will.retain { flags+=2_u8.pow(5) }
-            if will_qos_flags[0] == 1 { flags+=2_u8.pow(4) }
-            if will_qos_flags[1] == 1 { flags+=2_u8.pow(3) }
-            flags+=2_u8.pow(2);
-        }
-        let header = Header::new(
-            header::FixedHeader::Connect,
-            Some(VariableHeader::Connect(header::Connect {
-                protocol_name: EncodedString::new("MQTT"),
-                protocol_level: 4_u8,
-                connect_flags: flags,
-                keep_alive: Integer::new(0),
-            })),
+        let transport: Arc<dyn Transport> = Arc::from(transport::connect(&self.options)?);
+        let old = std::mem::replace(
+            &mut *self.transport.write().expect("FATAL: stream lock poisoned"),
+            transport.clone(),
         );
-        let will = self.will.clone();
-        let payload = Payload {
-            content: Some(payload::Payloads::Connect(ConnectPayload::new(
-                &self.client_id,
-                will.clone().map(|w| w.topic).as_deref(),
-                will.map(|w| w.message).as_deref(),
-                self.server_connection.username.clone().map(|u| u.value),
-                self.server_connection.password.clone().map(|u| u.value),
-            ))),
-        };
-        let packet = ControlPacket { header, payload };
-        self.tcp_stream
-            .as_ref()
-            .write_all(&packet.to_bytes())
-            .map_err(|_| {
-                crate::types::error::Error::ConnectionError
-            })?;
-        Ok(())
+        let _ = old.shutdown();
+        handshake(transport.as_ref(), &self.protocol, self.options.connect_timeout)?;
+        self.persist(&mut self.protocol())
     }
-    ///Runs the client loop with the provided callbacks.
-    pub fn do_loop<T>(&self, mut callbacks: Callbacks<T>) {
-        let mut bytes = std::collections::VecDeque::new();
-        'outer: loop {
-            let mut buf = [0_u8; 64];
-            while let Ok(n) = self.tcp_stream.as_ref().read(&mut buf) {
-                if n < buf.len() && n != 0 {
-                    bytes.extend(buf[..n].to_vec());
-                    break;
+    ///Reconnects following the reconnect policy, calling the reconnecting callback before each attempt.
+    ///Returns false if all allowed attempts failed, or the client loop was asked to stop or the client disconnected
+    ///on purpose meanwhile.
+    fn reconnect_with_backoff<T>(&self, callbacks: &mut Callbacks<T>) -> bool {
+        let mut attempt = 1;
+        while self.options.reconnect_policy.allows(attempt) {
+            if !self.sleep(self.options.reconnect_policy.delay(attempt)) {
+                return false;
+            }
+            if let Some(ref cb) = callbacks.reconnecting_callback {
+                cb(&mut callbacks.data, attempt);
+            }
+            if self.reconnect().is_ok() {
+                if *self.intent() == Intent::Disconnect {
+                    let _ = self.stream().shutdown();
+                    return false;
                 }
-                if n == 0 && self.intent_disconnect {
-                    if let Some(ref cb) = callbacks.disconnect_callback {
-                        cb(&mut callbacks.data, 0);
+                return true;
+            }
+            attempt += 1;
+        }
+        false
+    }
+    ///Handles bytes read from the server and writes the packets answering them.
+    fn handle_incoming(&self, bytes: &[u8]) -> crate::types::error::Result<()> {
+        let mut protocol = self.protocol();
+        let res = protocol.handle_incoming(bytes);
+        let _ = self.persist(&mut protocol);
+        res?;
+        flush(self.stream().as_ref(), &mut protocol)
+    }
+    ///Sends PINGREQ when the link has been idle for the keep-alive interval.
+    ///Fails if an earlier PINGREQ went unanswered and the connection should be treated as dead.
+    fn handle_timeout(&self) -> crate::types::error::Result<()> {
+        let mut protocol = self.protocol();
+        protocol.handle_timeout(Instant::now())?;
+        flush(self.stream().as_ref(), &mut protocol)
+    }
+    ///Calls the callbacks for the events raised since the last call.
+    fn dispatch<T>(&self, callbacks: &mut Callbacks<T>) {
+        let events: Vec<Event> = {
+            let mut protocol = self.protocol();
+            std::iter::from_fn(|| protocol.poll_event()).collect()
+        };
+        for event in events {
+            let (cb, rc) = match event {
+                Event::Connected(_) => (&callbacks.connect_callback, 0),
+                Event::Published(pid) => (&callbacks.publish_callback, pid.into()),
+                Event::Subscribed(pid) => (&callbacks.subscribe_callback, pid.into()),
+                Event::Unsubscribed(pid) => (&callbacks.unsubscribe_callback, pid.into()),
+                Event::Message(msg) => {
+                    if let Some(ref cb) = callbacks.message_callback {
+                        cb(&mut callbacks.data, msg);
                     }
-                    return;
+                    continue;
                 }
-                if n == 0 && !self.intent_disconnect {
-                    let _ = self.reconnect();
-                    continue 'outer;
+                Event::Disconnected => continue,
+            };
+            if let Some(cb) = cb {
+                cb(&mut callbacks.data, rc);
+            }
+        }
+    }
+    ///Runs the client loop with the provided callbacks.
+    ///
+    ///Returns once the client disconnects on purpose, once the connection is lost and every reconnect attempt failed,
+    ///or once `loop_stop` is called.
+    pub fn do_loop<T>(&self, mut callbacks: Callbacks<T>) {
+        loop {
+            self.dispatch(&mut callbacks);
+            if *self.intent() == Intent::StopLoop {
+                return;
+            }
+            let mut buf = [0_u8; 1024];
+            let stream = self.stream();
+            let timeout = self
+                .protocol()
+                .timeout(Instant::now())
+                .map_or(STOP_POLL_INTERVAL, |t| t.clamp(Duration::from_millis(1), STOP_POLL_INTERVAL));
+            let _ = stream.set_read_timeout(Some(timeout));
+            let res = match stream.read(&mut buf) {
+                Ok(n) if n > 0 => self.handle_incoming(&buf[..n]),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(()),
+                _ => Err(crate::types::error::Error::ConnectionError),
+            };
+            let res = res.and_then(|()| self.handle_timeout());
+            if res.is_ok() {
+                continue;
+            }
+            if *self.intent() == Intent::Run {
+                self.protocol().connection_lost();
+                let _ = stream.shutdown();
+                if self.reconnect_with_backoff(&mut callbacks) {
+                    continue;
                 }
-                bytes.extend(buf[..n].to_vec());
             }
-            let response = ControlPacket::from_bytes(&mut bytes);
-            if let Some(resp) = response {
-                match resp.header.fixed {
-                    header::FixedHeader::Unsuback => {
-                        if let Some(ref cb) = callbacks.unsubscribe_callback {
-                            let header::VariableHeader::Unsuback(unsub) = resp
-                                .header
-                                .variable
-                                .expect("FATAL: that should not appear in any circumstances")
-                            else {
-                                continue;
-                            };
-                            cb(&mut callbacks.data, unsub.packet_id.to_u16() as i32);
-                        }
-                    }
-                    header::FixedHeader::Suback => {
-                        if let Some(ref cb) = callbacks.subscribe_callback {
-                            let header::VariableHeader::Suback(sub) = resp
-                                .header
-                                .variable
-                                .expect("FATAL: that should not appear in any circumstances")
-                            else {
-                                continue;
-                            };
-                            cb(&mut callbacks.data, sub.packet_id.to_u16() as i32);
-                        }
-                    }
-                    header::FixedHeader::Pubcomp => {
-                        if let Some(ref cb) = callbacks.publish_callback {
-                            let header::VariableHeader::Pubcomp(publ) = resp
-                                .header
-                                .variable
-                                .expect("FATAL: that should not appear in any circumstances")
-                            else {
-                                continue;
-                            };
-                            cb(&mut callbacks.data, publ.packet_id.to_u16() as i32);
-                        }
-                    }
-                    header::FixedHeader::Pubrel => {
-                        if let Some(ref cb) = callbacks.publish_callback {
-                            let header::VariableHeader::Pubrec(publ) = resp
-                                .header
-                                .variable
-                                .expect("FATAL: that should not appear in any circumstances")
-                            else {
-                                continue;
-                            };
-                            cb(&mut callbacks.data, publ.packet_id.to_u16() as i32);
-                        }
-                    }
-                    header::FixedHeader::Pubrec => {
-                        if let Some(ref cb) = callbacks.publish_callback {
-                            let header::VariableHeader::Pubrec(publ) = resp
-                                .header
-                                .variable
-                                .expect("FATAL: that should not appear in any circumstances")
-                            else {
-                                continue;
-                            };
-                            cb(&mut callbacks.data, publ.packet_id.to_u16() as i32);
-                        }
-                    }
-                    header::FixedHeader::Puback => {
-                        if let Some(ref cb) = callbacks.publish_callback {
-                            let header::VariableHeader::Puback(publ) = resp
-                                .header
-                                .variable
-                                .expect("FATAL: that should not appear in any circumstances")
-                            else {
-                                continue;
-                            };
-                            cb(&mut callbacks.data, publ.packet_id.to_u16() as i32);
-                        }
-                    }
-                    header::FixedHeader::Connack => {
-                        if let Some(ref cb) = callbacks.connect_callback {
-                            let header::VariableHeader::Conack(conn) = resp
-                                .header
-                                .variable
-                                .expect("FATAL: that should not appear in any circumstances")
-                            else {
-                                continue;
-                            };
-                            cb(&mut callbacks.data, conn.connect_return_code as i32);
-                        }
-                    }
-                    header::FixedHeader::Publish(_, _, _) => {
-                        if let Some(ref cb) = callbacks.message_callback {
-                            cb(&mut callbacks.data, resp);
-                        }
-                    }
-                    header::FixedHeader::Pingresp => {}
-                    _ => {}
+            let rc = match *self.intent() {
+                Intent::StopLoop => return,
+                Intent::Disconnect => 0,
+                Intent::Run => 1,
+            };
+            if let Some(ref cb) = callbacks.disconnect_callback {
+                cb(&mut callbacks.data, rc);
+            }
+            return;
+        }
+    }
+    ///Starts a background network thread running the client loop with the callbacks built by `callbacks` on that thread,
+    ///so the client can keep being used from the calling thread meanwhile.
+    ///
+    ///Fails if the network thread is already running.
+    pub fn loop_start<T, F>(self: &Arc<Self>, callbacks: F) -> crate::types::error::Result<()>
+    where
+        F: FnOnce() -> Callbacks<'static, T> + Send + 'static,
+    {
+        let mut network_thread = self
+            .network_thread
+            .lock()
+            .expect("FATAL: network thread lock poisoned");
+        if network_thread.as_ref().is_some_and(|handle| !handle.is_finished()) {
+            return Err(crate::types::error::Error::LoopAlreadyStartedError);
+        }
+        let mut intent = self.intent();
+        if *intent == Intent::StopLoop {
+            *intent = Intent::Run;
+        }
+        drop(intent);
+        let client = Arc::clone(self);
+        *network_thread = Some(thread::spawn(move || client.do_loop(callbacks())));
+        Ok(())
+    }
+    ///Stops the background network thread started by `loop_start`, waiting for it to finish unless called from one of its callbacks.
+    ///The connection stays open and the loop can be started again; call `disconnect` to close it.
+    ///
+    ///Panics if a callback panicked on the network thread.
+    pub fn loop_stop(&self) {
+        let handle = self
+            .network_thread
+            .lock()
+            .expect("FATAL: network thread lock poisoned")
+            .take();
+        let mut intent = self.intent();
+        if *intent == Intent::Run {
+            *intent = Intent::StopLoop;
+            self.intent_changed.notify_all();
+        }
+        drop(intent);
+        let Some(handle) = handle.filter(|handle| handle.thread().id() != thread::current().id()) else {
+            return;
+        };
+        if let Err(e) = handle.join() {
+            std::panic::resume_unwind(e);
+        }
+    }
+}
+
+///Writes the bytes the protocol has to send, treating the connection as lost if that fails.
+fn flush(transport: &dyn Transport, protocol: &mut Protocol) -> crate::types::error::Result<()> {
+    let Some(bytes) = protocol.poll_transmit(Instant::now()) else {
+        return Ok(());
+    };
+    transport.write_all(&bytes).map_err(|_| {
+        protocol.connection_lost();
+        let _ = transport.shutdown();
+        crate::types::error::Error::RequestError
+    })
+}
+
+///Locks the protocol state machine.
+fn lock(protocol: &Mutex<Protocol>) -> std::sync::MutexGuard<'_, Protocol> {
+    protocol.lock().expect("FATAL: protocol lock poisoned")
+}
+
+///Sends CONNECT over a connected transport and waits for the CONNACK, failing if it does not arrive in time
+///or refuses the connection.
+///
+///The protocol is only locked while it starts the connection and handles the bytes read, not while waiting on the server.
+fn handshake(
+    transport: &dyn Transport,
+    protocol: &Mutex<Protocol>,
+    timeout: Duration,
+) -> crate::types::error::Result<()> {
+    let bytes = {
+        let mut protocol = lock(protocol);
+        protocol.connect(Instant::now())?;
+        protocol.poll_transmit(Instant::now())
+    };
+    if let Some(bytes) = bytes {
+        if transport.write_all(&bytes).is_err() {
+            lock(protocol).connection_lost();
+            let _ = transport.shutdown();
+            return Err(crate::types::error::Error::RequestError);
+        }
+    }
+    transport
+        .set_read_timeout(Some(timeout))
+        .map_err(|_| crate::types::error::Error::ConnectionError)?;
+    let res = read_connack(transport, protocol);
+    let _ = transport.set_read_timeout(None);
+    if res.is_err() {
+        lock(protocol).connection_lost();
+    }
+    res
+}
+
+///Reads from the server until the protocol has handled the CONNACK, then writes the packets it queued in answer,
+///such as retransmissions, before anything else can be sent.
+fn read_connack(transport: &dyn Transport, protocol: &Mutex<Protocol>) -> crate::types::error::Result<()> {
+    let mut buf = [0_u8; 1024];
+    loop {
+        let n = match transport.read(&mut buf) {
+            Ok(n) if n > 0 => n,
+            Ok(_) => return Err(crate::types::error::Error::ConnectionError),
+            Err(e) => return Err(transport::connection_error(e)),
+        };
+        let mut protocol = lock(protocol);
+        protocol.handle_incoming(&buf[..n])?;
+        if protocol.is_connected() {
+            return flush(transport, &mut protocol);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        collections::VecDeque,
+        net::{TcpListener, TcpStream},
+        rc::Rc,
+    };
+
+    use super::*;
+    use crate::{
+        offline_queue::OfflineQueue,
+        reconnect::ReconnectPolicy,
+        types::{
+            header::{self, VariableHeader},
+            payload, Acknowledgement, ControlPacket, Integer,
+        },
+    };
+
+    ///Represents the server side of a connection accepted by a test broker.
+    struct Peer {
+        stream: TcpStream,
+        bytes: VecDeque<u8>,
+    }
+
+    impl Peer {
+        fn accept(listener: &TcpListener) -> Self {
+            let (stream, _) = listener.accept().unwrap();
+            Self {
+                stream,
+                bytes: VecDeque::new(),
+            }
+        }
+        fn read_packet(&mut self) -> ControlPacket {
+            loop {
+                if let Some(packet) = ControlPacket::from_bytes(&mut self.bytes).unwrap() {
+                    return packet;
                 }
-            } else {
-                continue;
+                let mut buf = [0_u8; 256];
+                let n = self.stream.read(&mut buf).unwrap();
+                assert!(n > 0);
+                self.bytes.extend(&buf[..n]);
             }
         }
+        fn write(&mut self, bytes: &[u8]) {
+            self.stream.write_all(bytes).unwrap();
+        }
+    }
+
+    fn listen() -> (TcpListener, u32) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, port.into())
+    }
+
+    ///Represents a transport set up by the caller, recording every byte written through it.
+    #[derive(Debug)]
+    struct Recording {
+        stream: TcpStream,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Transport for Recording {
+        fn read(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.stream.read(buf)
+        }
+        fn write_all(&self, buf: &[u8]) -> std::io::Result<()> {
+            self.written.lock().unwrap().extend(buf);
+            self.stream.write_all(buf)
+        }
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+            Transport::set_read_timeout(&self.stream, timeout)
+        }
+        fn shutdown(&self) -> std::io::Result<()> {
+            Transport::shutdown(&self.stream)
+        }
+    }
+
+    fn options(port: u32) -> ConnectOptions {
+        ConnectOptions::new(&format!("mqtt://127.0.0.1:{port}?client_id=id")).unwrap()
+    }
+
+    fn broker(connack: [u8; 4]) -> (u32, thread::JoinHandle<ControlPacket>) {
+        let (listener, port) = listen();
+        let handle = thread::spawn(move || {
+            let mut peer = Peer::accept(&listener);
+            let connect = peer.read_packet();
+            peer.write(&connack);
+            connect
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn connect_test() {
+        let (port, handle) = broker([0x20, 0x02, 0x01, 0x00]);
+        let client = options(port)
+            .clean_session(false)
+            .keep_alive(30)
+            .connect()
+            .unwrap();
+        assert!(client.session_present());
+        let connect = handle.join().unwrap();
+        let Some(VariableHeader::Connect(connect)) = connect.header.variable else {
+            panic!("expected CONNECT");
+        };
+        assert_eq!(connect.keep_alive, Integer::new(30));
+    }
+    #[test]
+    fn connect_test1() {
+        let (port, handle) = broker([0x20, 0x02, 0x00, 0x04]);
+        let res = options(port).connect();
+        assert_eq!(
+            res.err(),
+            Some(crate::types::error::Error::ConnectionRefusedError(
+                header::ConnectReturnCode::BadUsernameOrPassword
+            ))
+        );
+        handle.join().unwrap();
+    }
+    #[test]
+    fn connect_with_test() {
+        let (port, handle) = broker([0x20, 0x02, 0x00, 0x00]);
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let transport = Recording {
+            stream: TcpStream::connect(format!("127.0.0.1:{port}")).unwrap(),
+            written: written.clone(),
+        };
+        let client = ConnectOptions::new("mqtt://unused.invalid?client_id=id")
+            .unwrap()
+            .connect_with(Box::new(transport))
+            .unwrap();
+        let connect = handle.join().unwrap();
+        assert_eq!(*written.lock().unwrap(), connect.to_bytes().unwrap());
+        assert_eq!(client.host(), "unused.invalid");
+    }
+    #[test]
+    fn limits_test() {
+        let (listener, port) = listen();
+        let handle = thread::spawn(move || {
+            let mut peer = Peer::accept(&listener);
+            peer.read_packet();
+            peer.write(&[0x20, 0x02, 0x00, 0x00]);
+            peer.read_packet()
+        });
+        let client = options(port)
+            .max_in_flight(1)
+            .max_packet_size(16)
+            .connect()
+            .unwrap();
+        assert_eq!(client.publish("a", "one", QOS::One, false), Ok(1));
+        assert_eq!(
+            client.publish("a", "two", QOS::One, false),
+            Err(crate::types::error::Error::InFlightLimitExceededError)
+        );
+        assert_eq!(
+            client.publish("a", "far too long to fit", QOS::Zero, false),
+            Err(crate::types::error::Error::PacketSizeExceededError)
+        );
+        let publish = handle.join().unwrap();
+        assert_eq!(publish.payload.content, Some(payload::Payloads::Publish(b"one".to_vec())));
+    }
+    #[test]
+    fn reconnect_test() {
+        let (listener, port) = listen();
+        let handle = thread::spawn(move || {
+            let mut peer = Peer::accept(&listener);
+            peer.read_packet();
+            peer.write(&[0x20, 0x02, 0x00, 0x00]);
+            drop(peer);
+            let mut peer = Peer::accept(&listener);
+            peer.read_packet();
+            peer.write(&[0x20, 0x02, 0x00, 0x00]);
+            drop(listener);
+        });
+        let client = options(port)
+            .keep_alive(0)
+            .reconnect_policy(ReconnectPolicy {
+                initial_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(1),
+                multiplier: 1.0,
+                jitter: 0.0,
+                max_attempts: Some(2),
+            })
+            .connect()
+            .unwrap();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut callbacks = Callbacks::new(events.clone());
+        callbacks.on_connect(|events, rc| events.borrow_mut().push(format!("connect {rc}")));
+        callbacks.on_reconnecting(|events, attempt| {
+            events.borrow_mut().push(format!("reconnecting {attempt}"))
+        });
+        callbacks.on_disconnect(|events, rc| events.borrow_mut().push(format!("disconnect {rc}")));
+        client.do_loop(callbacks);
+        handle.join().unwrap();
+        assert_eq!(
+            *events.borrow(),
+            [
+                "connect 0",
+                "reconnecting 1",
+                "connect 0",
+                "reconnecting 1",
+                "reconnecting 2",
+                "disconnect 1"
+            ]
+        );
+    }
+    #[test]
+    fn reconnect_test1() {
+        let (listener, port) = listen();
+        let (connecting, wait) = std::sync::mpsc::channel();
+        let (resume, resumed) = std::sync::mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            let mut peer = Peer::accept(&listener);
+            peer.read_packet();
+            peer.write(&[0x20, 0x02, 0x00, 0x00]);
+            drop(peer);
+            let mut peer = Peer::accept(&listener);
+            drop(listener);
+            connecting.send(peer.read_packet()).unwrap();
+            resumed.recv().unwrap();
+            peer.write(&[0x20, 0x02, 0x00, 0x00]);
+        });
+        let client = Arc::new(
+            options(port)
+                .keep_alive(0)
+                .reconnect_policy(ReconnectPolicy {
+                    max_attempts: Some(1),
+                    ..Default::default()
+                })
+                .connect()
+                .unwrap(),
+        );
+        client.loop_start(|| Callbacks::new(())).unwrap();
+        assert_eq!(wait.recv().unwrap().header.fixed, header::FixedHeader::Connect);
+        assert_eq!(
+            client.publish("a", "early", QOS::Zero, false),
+            Err(crate::types::error::Error::RequestError)
+        );
+        assert!(!client.session_present());
+        resume.send(()).unwrap();
+        handle.join().unwrap();
+        client.loop_stop();
+    }
+    #[test]
+    fn resubscribe_test() {
+        let (listener, port) = listen();
+        let handle = thread::spawn(move || {
+            let mut peer = Peer::accept(&listener);
+            peer.read_packet();
+            peer.write(&[0x20, 0x02, 0x00, 0x00]);
+            peer.read_packet();
+            peer.write(&[0x90, 0x03, 0x00, 0x01, 0x01]);
+            peer.read_packet();
+            peer.write(&[0x90, 0x03, 0x00, 0x02, 0x02]);
+            drop(peer);
+            let mut peer = Peer::accept(&listener);
+            peer.read_packet();
+            peer.write(&[0x20, 0x02, 0x00, 0x00]);
+            drop(listener);
+            peer.read_packet()
+        });
+        let client = options(port)
+            .clean_session(false)
+            .keep_alive(0)
+            .reconnect_policy(ReconnectPolicy {
+                max_attempts: Some(1),
+                ..Default::default()
+            })
+            .connect()
+            .unwrap();
+        client.subscribe("a/#", QOS::One).unwrap();
+        client.subscribe("b/+", QOS::Two).unwrap();
+        assert!(client.subscriptions().is_empty());
+        client.do_loop(Callbacks::new(()));
+        assert_eq!(
+            client.subscriptions(),
+            [
+                SubscribePayload::new("a/#", QOS::One),
+                SubscribePayload::new("b/+", QOS::Two)
+            ]
+        );
+        let resubscribe = handle.join().unwrap();
+        assert_eq!(resubscribe.header.fixed, header::FixedHeader::Subscribe);
+        assert_eq!(
+            resubscribe.payload.content,
+            Some(payload::Payloads::Subscribe(client.subscriptions()))
+        );
+    }
+    #[test]
+    fn retransmit_test() {
+        let (listener, port) = listen();
+        let handle = thread::spawn(move || {
+            let mut peer = Peer::accept(&listener);
+            peer.read_packet();
+            peer.write(&[0x20, 0x02, 0x00, 0x00]);
+            let first = peer.read_packet();
+            peer.read_packet();
+            peer.write(&[0x50, 0x02, 0x00, 0x02]);
+            let pubrel = peer.read_packet();
+            drop(peer);
+            let mut peer = Peer::accept(&listener);
+            peer.read_packet();
+            peer.write(&[0x20, 0x02, 0x01, 0x00]);
+            drop(listener);
+            (first, pubrel, peer.read_packet(), peer.read_packet())
+        });
+        let client = options(port)
+            .clean_session(false)
+            .keep_alive(0)
+            .reconnect_policy(ReconnectPolicy {
+                max_attempts: Some(1),
+                ..Default::default()
+            })
+            .connect()
+            .unwrap();
+        assert_eq!(client.publish("a", "one", QOS::One, false), Ok(1));
+        assert_eq!(client.publish("b", "two", QOS::Two, false), Ok(2));
+        client.do_loop(Callbacks::new(()));
+        let (first, pubrel, republish, repubrel) = handle.join().unwrap();
+        assert_eq!(pubrel, ControlPacket::acknowledgement(Acknowledgement::Pubrel, Integer::new(2)));
+        assert_eq!(republish.header.fixed, header::FixedHeader::Publish(true, QOS::One, false));
+        assert_eq!(republish.header.variable, first.header.variable);
+        assert_eq!(republish.payload, first.payload);
+        assert_eq!(repubrel, pubrel);
+    }
+    #[test]
+    fn pubrel_test() {
+        let (listener, port) = listen();
+        let handle = thread::spawn(move || {
+            let mut peer = Peer::accept(&listener);
+            peer.read_packet();
+            peer.write(&[0x20, 0x02, 0x00, 0x00]);
+            peer.read_packet();
+            peer.write(&[0x50, 0x02, 0x00, 0x01]);
+            let pubrel = peer.read_packet();
+            peer.write(&[0x70, 0x02, 0x00, 0x01]);
+            pubrel
+        });
+        let client = options(port)
+            .keep_alive(0)
+            .reconnect_policy(ReconnectPolicy {
+                max_attempts: Some(0),
+                ..Default::default()
+            })
+            .connect()
+            .unwrap();
+        assert_eq!(client.publish("a", "one", QOS::Two, false), Ok(1));
+        let published = Rc::new(RefCell::new(Vec::new()));
+        let mut callbacks = Callbacks::new(published.clone());
+        callbacks.on_publish(|published, pid| published.borrow_mut().push(pid));
+        client.do_loop(callbacks);
+        let pubrel = handle.join().unwrap();
+        assert_eq!(pubrel, ControlPacket::acknowledgement(Acknowledgement::Pubrel, Integer::new(1)));
+        assert_eq!(*published.borrow(), [1]);
+        assert!(!client.protocol().packet_ids().is_in_flight(1));
+    }
+    #[test]
+    fn inbound_qos_test() {
+        let (listener, port) = listen();
+        let handle = thread::spawn(move || {
+            let mut peer = Peer::accept(&listener);
+            peer.read_packet();
+            peer.write(&[0x20, 0x02, 0x00, 0x00]);
+            peer.write(&[0x32, 0x06, 0x00, 0x01, b'a', 0x00, 0x01, b'x']);
+            let puback = peer.read_packet();
+            peer.write(&[0x34, 0x06, 0x00, 0x01, b'b', 0x00, 0x02, b'y']);
+            let pubrec = peer.read_packet();
+            peer.write(&[0x3c, 0x06, 0x00, 0x01, b'b', 0x00, 0x02, b'y']);
+            let repubrec = peer.read_packet();
+            peer.write(&[0x62, 0x02, 0x00, 0x02]);
+            (puback, pubrec, repubrec, peer.read_packet())
+        });
+        let client = options(port)
+            .keep_alive(0)
+            .reconnect_policy(ReconnectPolicy {
+                max_attempts: Some(0),
+                ..Default::default()
+            })
+            .connect()
+            .unwrap();
+        let messages = Rc::new(RefCell::new(Vec::new()));
+        let mut callbacks = Callbacks::new(messages.clone());
+        callbacks.on_message(|messages, msg| messages.borrow_mut().push(msg.topic().to_owned()));
+        client.do_loop(callbacks);
+        let (puback, pubrec, repubrec, pubcomp) = handle.join().unwrap();
+        assert_eq!(puback, ControlPacket::acknowledgement(Acknowledgement::Puback, Integer::new(1)));
+        assert_eq!(pubrec, ControlPacket::acknowledgement(Acknowledgement::Pubrec, Integer::new(2)));
+        assert_eq!(repubrec, pubrec);
+        assert_eq!(pubcomp, ControlPacket::acknowledgement(Acknowledgement::Pubcomp, Integer::new(2)));
+        assert_eq!(*messages.borrow(), ["a", "b"]);
+    }
+    #[test]
+    fn keep_alive_test() {
+        let (listener, port) = listen();
+        let (done, wait) = std::sync::mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            let mut peer = Peer::accept(&listener);
+            peer.read_packet();
+            peer.write(&[0x20, 0x02, 0x00, 0x00]);
+            drop(listener);
+            let pingreq = peer.read_packet();
+            let _ = wait.recv();
+            pingreq
+        });
+        let client = options(port)
+            .keep_alive(1)
+            .reconnect_policy(ReconnectPolicy {
+                initial_delay: Duration::from_millis(1),
+                max_attempts: Some(1),
+                ..Default::default()
+            })
+            .connect()
+            .unwrap();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut callbacks = Callbacks::new(events.clone());
+        callbacks.on_reconnecting(|events, attempt| {
+            events.borrow_mut().push(format!("reconnecting {attempt}"))
+        });
+        callbacks.on_disconnect(|events, rc| events.borrow_mut().push(format!("disconnect {rc}")));
+        let start = Instant::now();
+        client.do_loop(callbacks);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        done.send(()).unwrap();
+        let pingreq = handle.join().unwrap();
+        assert_eq!(pingreq.header.fixed, header::FixedHeader::Pingreq);
+        assert_eq!(*events.borrow(), ["reconnecting 1", "disconnect 1"]);
+    }
+    #[test]
+    fn keep_alive_test1() {
+        let (listener, port) = listen();
+        let handle = thread::spawn(move || {
+            let mut peer = Peer::accept(&listener);
+            peer.read_packet();
+            peer.write(&[0x20, 0x02, 0x00, 0x00]);
+            let connected = Instant::now();
+            let mut reader = Peer {
+                stream: peer.stream.try_clone().unwrap(),
+                bytes: VecDeque::new(),
+            };
+            let pinged = Arc::new(std::sync::atomic::AtomicBool::new(false));
+            let streaming = {
+                let pinged = pinged.clone();
+                thread::spawn(move || {
+                    let start = Instant::now();
+                    while !pinged.load(std::sync::atomic::Ordering::SeqCst) && start.elapsed() < Duration::from_secs(3) {
+                        peer.write(&[0x30, 0x05, 0x00, 0x01, b'a', b'h', b'i']);
+                        thread::sleep(Duration::from_micros(200));
+                    }
+                    peer.write(&[0xd0, 0x00]);
+                })
+            };
+            let pingreq = reader.read_packet();
+            let elapsed = connected.elapsed();
+            pinged.store(true, std::sync::atomic::Ordering::SeqCst);
+            streaming.join().unwrap();
+            (pingreq, elapsed)
+        });
+        let client = options(port)
+            .keep_alive(1)
+            .reconnect_policy(ReconnectPolicy {
+                max_attempts: Some(0),
+                ..Default::default()
+            })
+            .connect()
+            .unwrap();
+        let messages = Rc::new(RefCell::new(0));
+        let mut callbacks = Callbacks::new(messages.clone());
+        callbacks.on_message(|messages, _| *messages.borrow_mut() += 1);
+        client.do_loop(callbacks);
+        let (pingreq, elapsed) = handle.join().unwrap();
+        assert_eq!(pingreq.header.fixed, header::FixedHeader::Pingreq);
+        assert!(elapsed < Duration::from_millis(1500));
+        assert!(*messages.borrow() > 10);
+    }
+    #[test]
+    fn offline_queue_test() {
+        let (listener, port) = listen();
+        let handle = thread::spawn(move || {
+            let mut peer = Peer::accept(&listener);
+            peer.read_packet();
+            peer.write(&[0x20, 0x02, 0x00, 0x00]);
+            drop(peer);
+            let mut peer = Peer::accept(&listener);
+            peer.read_packet();
+            peer.write(&[0x20, 0x02, 0x00, 0x00]);
+            drop(listener);
+            (peer.read_packet(), peer.read_packet())
+        });
+        let client = options(port)
+            .keep_alive(0)
+            .reconnect_policy(ReconnectPolicy {
+                max_attempts: Some(1),
+                ..Default::default()
+            })
+            .offline_queue(OfflineQueue::new(
+                2,
+                1024,
+                crate::offline_queue::OverflowPolicy::DropOldest,
+            ))
+            .connect()
+            .unwrap();
+        let results = RefCell::new(Vec::new());
+        let mut callbacks = Callbacks::new((&client, &results));
+        callbacks.on_reconnecting(|(client, results), _| {
+            let mut results = results.borrow_mut();
+            if results.is_empty() {
+                results.push(client.publish("a", "one", QOS::One, false));
+                results.push(client.publish("b", "two", QOS::One, false));
+                results.push(client.publish("c", "three", QOS::Zero, false));
+            }
+        });
+        client.do_loop(callbacks);
+        let (first, second) = handle.join().unwrap();
+        assert_eq!(*results.borrow(), [Ok(1), Ok(2), Ok(0)]);
+        assert_eq!(first.header.fixed, header::FixedHeader::Publish(false, QOS::One, false));
+        assert_eq!(first.payload.content, Some(payload::Payloads::Publish(b"two".to_vec())));
+        assert_eq!(second.payload.content, Some(payload::Payloads::Publish(b"three".to_vec())));
+        let protocol = client.protocol();
+        assert!(!protocol.packet_ids().is_in_flight(1));
+        assert!(protocol.packet_ids().is_in_flight(2));
+    }
+    #[test]
+    fn loop_test() {
+        let (listener, port) = listen();
+        let handle = thread::spawn(move || {
+            let mut peer = Peer::accept(&listener);
+            peer.read_packet();
+            peer.write(&[0x20, 0x02, 0x00, 0x00]);
+            peer.write(&[0x30, 0x05, 0x00, 0x01, b'a', b'h', b'i']);
+            let publish = peer.read_packet();
+            peer.write(&[0x40, 0x02, 0x00, 0x01]);
+            (publish, peer.read_packet(), peer.read_packet())
+        });
+        let client = Arc::new(options(port).keep_alive(0).connect().unwrap());
+        let (sender, events) = std::sync::mpsc::channel();
+        client
+            .loop_start(move || {
+                let mut callbacks = Callbacks::new(sender);
+                callbacks.on_connect(|sender, rc| sender.send(format!("connect {rc}")).unwrap());
+                callbacks.on_message(|sender, msg| sender.send(format!("message {}", msg.topic())).unwrap());
+                callbacks.on_publish(|sender, pid| sender.send(format!("publish {pid}")).unwrap());
+                callbacks.on_disconnect(|sender, rc| sender.send(format!("disconnect {rc}")).unwrap());
+                callbacks
+            })
+            .unwrap();
+        assert_eq!(
+            client.loop_start(|| Callbacks::new(())),
+            Err(crate::types::error::Error::LoopAlreadyStartedError)
+        );
+        assert_eq!(events.recv().unwrap(), "connect 0");
+        assert_eq!(events.recv().unwrap(), "message a");
+        assert_eq!(client.publish("b", "one", QOS::One, false), Ok(1));
+        assert_eq!(events.recv().unwrap(), "publish 1");
+        client.loop_stop();
+        assert!(events.recv().is_err());
+        assert_eq!(client.publish("c", "two", QOS::Zero, false), Ok(0));
+        let (sender, events) = std::sync::mpsc::channel();
+        client
+            .loop_start(move || {
+                let mut callbacks = Callbacks::new(sender);
+                callbacks.on_disconnect(|sender, rc| sender.send(format!("disconnect {rc}")).unwrap());
+                callbacks
+            })
+            .unwrap();
+        client.disconnect().unwrap();
+        assert_eq!(events.recv().unwrap(), "disconnect 0");
+        client.loop_stop();
+        let (publish, second, disconnect) = handle.join().unwrap();
+        assert_eq!(publish.payload.content, Some(payload::Payloads::Publish(b"one".to_vec())));
+        assert_eq!(second.payload.content, Some(payload::Payloads::Publish(b"two".to_vec())));
+        assert_eq!(disconnect.header.fixed, header::FixedHeader::Disconnect);
+        assert_eq!(Arc::strong_count(&client), 1);
+    }
+    #[test]
+    fn session_store_test() {
+        let path = std::env::temp_dir().join(format!("wiux-client-store-{}", std::process::id()));
+        let (listener, port) = listen();
+        let handle = thread::spawn(move || {
+            let mut peer = Peer::accept(&listener);
+            peer.read_packet();
+            peer.write(&[0x20, 0x02, 0x00, 0x00]);
+            let publish = peer.read_packet();
+            let mut peer = Peer::accept(&listener);
+            peer.read_packet();
+            peer.write(&[0x20, 0x02, 0x01, 0x00]);
+            (publish, peer.read_packet())
+        });
+        let client = options(port)
+            .clean_session(false)
+            .keep_alive(0)
+            .session_store(Box::new(crate::store::FileSessionStore::new(&path)))
+            .connect()
+            .unwrap();
+        assert_eq!(client.publish("a", "kept", QOS::One, false), Ok(1));
+        drop(client);
+        let client = options(port)
+            .clean_session(false)
+            .keep_alive(0)
+            .session_store(Box::new(crate::store::FileSessionStore::new(&path)))
+            .connect()
+            .unwrap();
+        let (publish, republish) = handle.join().unwrap();
+        assert_eq!(republish.header.fixed, header::FixedHeader::Publish(true, QOS::One, false));
+        assert_eq!(republish.header.variable, publish.header.variable);
+        assert!(client.protocol().packet_ids().is_in_flight(1));
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,117 @@
+use std::time::{Duration, Instant};
+
+///Represents what the client should do to keep the connection alive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepAliveState {
+    ///Nothing is due yet.
+    Idle,
+    ///The link has been idle for the keep-alive interval and a PINGREQ should be sent.
+    Ping,
+    ///A PINGREQ was not answered in time and the connection should be treated as dead.
+    Expired,
+}
+
+///Represents the keep-alive timer of a connection, tracking when a packet was last sent and whether a PINGREQ is outstanding.
+#[derive(Debug, Clone, Copy)]
+pub struct KeepAlive {
+    interval: Duration,
+    last_sent: Instant,
+    ping_sent: Option<Instant>,
+}
+
+impl KeepAlive {
+    ///Creates a new KeepAlive instance with an interval in seconds. An interval of 0 disables keep-alive.
+    pub fn new(interval: u16, now: Instant) -> Self {
+        Self {
+            interval: Duration::from_secs(interval.into()),
+            last_sent: now,
+            ping_sent: None,
+        }
+    }
+    ///Returns the keep-alive interval in seconds.
+    pub fn interval(&self) -> u16 {
+        self.interval.as_secs() as u16
+    }
+    ///Restarts the timer, forgetting any outstanding PINGREQ.
+    pub fn reset(&mut self, now: Instant) {
+        self.last_sent = now;
+        self.ping_sent = None;
+    }
+    ///Records that a packet was sent to the server.
+    pub fn on_send(&mut self, now: Instant) {
+        self.last_sent = now;
+    }
+    ///Records that a PINGREQ was sent to the server.
+    pub fn on_ping(&mut self, now: Instant) {
+        self.last_sent = now;
+        self.ping_sent = Some(now);
+    }
+    ///Records that a PINGRESP was received from the server.
+    pub fn on_pingresp(&mut self) {
+        self.ping_sent = None;
+    }
+    ///Returns what is due at the given time.
+    pub fn poll(&self, now: Instant) -> KeepAliveState {
+        if self.interval.is_zero() {
+            return KeepAliveState::Idle;
+        }
+        match self.ping_sent {
+            Some(sent) if now.duration_since(sent) >= self.interval => KeepAliveState::Expired,
+            Some(_) => KeepAliveState::Idle,
+            None if now.duration_since(self.last_sent) >= self.interval => KeepAliveState::Ping,
+            None => KeepAliveState::Idle,
+        }
+    }
+    ///Returns how long to wait from the given time until something is due, or None if keep-alive is disabled.
+    pub fn timeout(&self, now: Instant) -> Option<Duration> {
+        if self.interval.is_zero() {
+            return None;
+        }
+        let deadline = self.ping_sent.unwrap_or(self.last_sent) + self.interval;
+        Some(deadline.saturating_duration_since(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_alive_test() {
+        let start = Instant::now();
+        let mut keep_alive = KeepAlive::new(10, start);
+        assert_eq!(keep_alive.poll(start + Duration::from_secs(9)), KeepAliveState::Idle);
+        assert_eq!(
+            keep_alive.timeout(start + Duration::from_secs(4)),
+            Some(Duration::from_secs(6))
+        );
+        keep_alive.on_send(start + Duration::from_secs(5));
+        assert_eq!(keep_alive.poll(start + Duration::from_secs(10)), KeepAliveState::Idle);
+        assert_eq!(keep_alive.poll(start + Duration::from_secs(15)), KeepAliveState::Ping);
+        keep_alive.on_ping(start + Duration::from_secs(15));
+        assert_eq!(keep_alive.poll(start + Duration::from_secs(24)), KeepAliveState::Idle);
+        keep_alive.on_pingresp();
+        assert_eq!(keep_alive.poll(start + Duration::from_secs(25)), KeepAliveState::Ping);
+    }
+    #[test]
+    fn keep_alive_test1() {
+        let start = Instant::now();
+        let mut keep_alive = KeepAlive::new(10, start);
+        keep_alive.on_ping(start + Duration::from_secs(10));
+        keep_alive.on_send(start + Duration::from_secs(12));
+        assert_eq!(
+            keep_alive.timeout(start + Duration::from_secs(12)),
+            Some(Duration::from_secs(8))
+        );
+        assert_eq!(keep_alive.poll(start + Duration::from_secs(20)), KeepAliveState::Expired);
+        keep_alive.reset(start + Duration::from_secs(20));
+        assert_eq!(keep_alive.poll(start + Duration::from_secs(20)), KeepAliveState::Idle);
+    }
+    #[test]
+    fn keep_alive_test2() {
+        let start = Instant::now();
+        let keep_alive = KeepAlive::new(0, start);
+        assert_eq!(keep_alive.poll(start + Duration::from_secs(3600)), KeepAliveState::Idle);
+        assert_eq!(keep_alive.timeout(start), None);
+    }
+}
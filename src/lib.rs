@@ -1,3 +1,21 @@
 pub mod types;
+#[cfg(feature = "async")]
+pub mod async_client;
 pub mod client;
+pub mod keep_alive;
+pub mod offline_queue;
+pub mod options;
+pub mod packet_id;
+pub mod protocol;
+pub mod proxy;
+pub mod reconnect;
+pub mod session;
+pub mod store;
 pub mod topic_matcher;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transport;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+mod random;
+mod uri;
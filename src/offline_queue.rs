@@ -0,0 +1,144 @@
+use std::collections::VecDeque;
+
+use crate::types::ControlPacket;
+
+///Represents what happens when a publish does not fit into a full offline queue.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    ///Drop the oldest queued publishes until the new one fits.
+    #[default]
+    DropOldest,
+    ///Reject the new publish.
+    Reject,
+}
+
+///Represents a bounded queue of PUBLISH packets made while the client is disconnected,
+///flushed in order once the client is connected again.
+#[derive(Debug, Clone)]
+pub struct OfflineQueue {
+    max_messages: usize,
+    max_bytes: usize,
+    policy: OverflowPolicy,
+    packets: VecDeque<(Option<u16>, ControlPacket, usize)>,
+    bytes: usize,
+}
+
+impl OfflineQueue {
+    ///Creates a new OfflineQueue instance holding at most `max_messages` packets and `max_bytes` encoded bytes.
+    pub fn new(max_messages: usize, max_bytes: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            max_messages,
+            max_bytes,
+            policy,
+            packets: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+    ///Queues a PUBLISH packet with its packet id.
+    ///
+    ///Returns the packet ids of the publishes dropped to make room for it.
+    ///Fails if the packet can not fit, or if the queue is full and the policy is to reject.
+    pub fn push(
+        &mut self,
+        packet_id: Option<u16>,
+        packet: ControlPacket,
+    ) -> crate::types::error::Result<Vec<Option<u16>>> {
+        let len = packet.to_bytes()?.len();
+        if len > self.max_bytes || self.max_messages == 0 {
+            return Err(crate::types::error::Error::OfflineQueueFullError);
+        }
+        let full = |queue: &Self| {
+            queue.packets.len() >= queue.max_messages || queue.bytes + len > queue.max_bytes
+        };
+        if full(self) && self.policy == OverflowPolicy::Reject {
+            return Err(crate::types::error::Error::OfflineQueueFullError);
+        }
+        let mut dropped = Vec::new();
+        while full(self) {
+            let Some((pid, _, old_len)) = self.packets.pop_front() else {
+                break;
+            };
+            self.bytes -= old_len;
+            dropped.push(pid);
+        }
+        self.bytes += len;
+        self.packets.push_back((packet_id, packet, len));
+        Ok(dropped)
+    }
+    ///Removes the oldest queued packet with its packet id.
+    pub fn pop(&mut self) -> Option<(Option<u16>, ControlPacket)> {
+        let (pid, packet, len) = self.packets.pop_front()?;
+        self.bytes -= len;
+        Some((pid, packet))
+    }
+    ///Puts a packet back at the front of the queue, such as one that failed to send while flushing.
+    pub fn push_front(&mut self, packet_id: Option<u16>, packet: ControlPacket) {
+        let len = packet.to_bytes().map(|b| b.len()).unwrap_or_default();
+        self.bytes += len;
+        self.packets.push_front((packet_id, packet, len));
+    }
+    ///Returns the number of queued QoS 1 and QoS 2 packets, which hold a packet id.
+    pub fn in_flight(&self) -> usize {
+        self.packets.iter().filter(|(pid, _, _)| pid.is_some()).count()
+    }
+    ///Returns the number of queued packets.
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+    ///Returns true if no packets are queued.
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+    ///Returns the number of encoded bytes queued.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{
+        header::{FixedHeader, Header},
+        payload::{Payload, Payloads},
+    };
+
+    fn packet(payload_len: usize) -> ControlPacket {
+        ControlPacket::new(
+            Header::new(FixedHeader::Publish(false, crate::types::QOS::Zero, false), None),
+            Payload {
+                content: Some(Payloads::Publish(vec![0; payload_len])),
+            },
+        )
+    }
+
+    #[test]
+    fn drop_oldest_test() {
+        let mut queue = OfflineQueue::new(2, 100, OverflowPolicy::DropOldest);
+        assert_eq!(queue.push(Some(1), packet(8)), Ok(vec![]));
+        assert_eq!(queue.push(Some(2), packet(8)), Ok(vec![]));
+        assert_eq!(queue.push(None, packet(8)), Ok(vec![Some(1)]));
+        assert_eq!(queue.bytes(), 20);
+        assert_eq!(queue.push(Some(4), packet(90)), Ok(vec![Some(2), None]));
+        assert_eq!(queue.len(), 1);
+        assert_eq!(
+            queue.push(Some(5), packet(99)),
+            Err(crate::types::error::Error::OfflineQueueFullError)
+        );
+        assert_eq!(queue.pop(), Some((Some(4), packet(90))));
+        assert!(queue.is_empty());
+        assert_eq!(queue.bytes(), 0);
+    }
+    #[test]
+    fn reject_test() {
+        let mut queue = OfflineQueue::new(1, 100, OverflowPolicy::Reject);
+        assert_eq!(queue.push(Some(1), packet(8)), Ok(vec![]));
+        assert_eq!(
+            queue.push(Some(2), packet(8)),
+            Err(crate::types::error::Error::OfflineQueueFullError)
+        );
+        let (pid, packet) = queue.pop().unwrap();
+        queue.push_front(pid, packet.clone());
+        assert_eq!(queue.pop(), Some((Some(1), packet)));
+    }
+}
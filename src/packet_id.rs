@@ -0,0 +1,83 @@
+use std::collections::HashSet;
+
+///Represents a packet id allocator, handing out non-zero 16-bit ids and skipping the ones still in flight.
+#[derive(Debug, Default, Clone)]
+pub struct PacketIdAllocator {
+    last: u16,
+    in_flight: HashSet<u16>,
+}
+
+impl PacketIdAllocator {
+    ///Creates a new PacketIdAllocator instance with no ids in flight.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    ///Allocates the next free packet id and marks it as in flight.
+    ///
+    ///Ids are handed out in increasing order, wrapping around after 65535 and never returning 0.
+    ///Fails if all 65535 ids are in flight.
+    pub fn allocate(&mut self) -> crate::types::error::Result<u16> {
+        if self.in_flight.len() >= u16::MAX as usize {
+            return Err(crate::types::error::Error::PacketIdExhaustedError);
+        }
+        loop {
+            self.last = self.last.checked_add(1).unwrap_or(1);
+            if self.in_flight.insert(self.last) {
+                return Ok(self.last);
+            }
+        }
+    }
+    ///Marks a specific packet id as in flight, such as one restored from a session store.
+    ///Returns false if the id is 0 or already in flight.
+    pub fn reserve(&mut self, packet_id: u16) -> bool {
+        packet_id != 0 && self.in_flight.insert(packet_id)
+    }
+    ///Releases a packet id so it can be allocated again. Returns false if the id was not in flight.
+    pub fn release(&mut self, packet_id: u16) -> bool {
+        self.in_flight.remove(&packet_id)
+    }
+    ///Returns true if the packet id is currently in flight.
+    pub fn is_in_flight(&self, packet_id: u16) -> bool {
+        self.in_flight.contains(&packet_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocate_test() {
+        let mut ids = PacketIdAllocator::new();
+        assert_eq!(ids.allocate(), Ok(1));
+        assert_eq!(ids.allocate(), Ok(2));
+        assert!(ids.release(1));
+        assert!(!ids.release(1));
+        assert_eq!(ids.allocate(), Ok(3));
+    }
+    #[test]
+    fn allocate_test1() {
+        let mut ids = PacketIdAllocator::new();
+        ids.last = u16::MAX - 1;
+        assert_eq!(ids.allocate(), Ok(u16::MAX));
+        assert_eq!(ids.allocate(), Ok(1));
+        assert!(ids.reserve(2));
+        assert!(!ids.reserve(2));
+        assert!(!ids.reserve(0));
+        assert_eq!(ids.allocate(), Ok(3));
+        assert!(ids.is_in_flight(2));
+    }
+    #[test]
+    fn allocate_test2() {
+        let mut ids = PacketIdAllocator::new();
+        for _ in 0..u16::MAX {
+            ids.allocate().unwrap();
+        }
+        assert_eq!(
+            ids.allocate(),
+            Err(crate::types::error::Error::PacketIdExhaustedError)
+        );
+        ids.release(42);
+        assert_eq!(ids.allocate(), Ok(42));
+    }
+}
@@ -0,0 +1,694 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    keep_alive::{KeepAlive, KeepAliveState},
+    offline_queue::OfflineQueue,
+    options::ConnectOptions,
+    packet_id::PacketIdAllocator,
+    session::Session,
+    types::{
+        header::{self, Header, VariableHeader},
+        message::Message,
+        payload::{self, Payload, SubscribePayload},
+        Acknowledgement, ControlPacket, EncodedString, Integer, VariableByteInteger, QOS,
+    },
+};
+
+///Represents an event raised by the protocol state machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    ///The server accepted the connection, with whether it resumed a stored session.
+    Connected(bool),
+    ///A message arrived on a subscribed topic.
+    Message(Message),
+    ///A QoS 1 or QoS 2 publication with the given packet id completed.
+    Published(u16),
+    ///The server acknowledged the SUBSCRIBE with the given packet id.
+    Subscribed(u16),
+    ///The server acknowledged the UNSUBSCRIBE with the given packet id.
+    Unsubscribed(u16),
+    ///The client disconnected on purpose.
+    Disconnected,
+}
+
+///Represents the state of the connection, as far as the protocol knows it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum State {
+    #[default]
+    Disconnected,
+    Connecting,
+    Connected,
+}
+
+///Represents the MQTT protocol state machine of a client, free of any I/O.
+///
+///Incoming bytes are fed in with `handle_incoming` and the passing of time with `handle_timeout`.
+///Bytes to write to the server are taken with `poll_transmit` and application events with `poll_event`.
+///The bytes taken must be written in order before the next call that takes bytes, or the connection reported lost.
+#[derive(Debug)]
+pub struct Protocol {
+    connect: ControlPacket,
+    clean_session: bool,
+    max_in_flight: Option<usize>,
+    max_packet_size: Option<usize>,
+    state: State,
+    session_present: bool,
+    packet_ids: PacketIdAllocator,
+    session: Session,
+    session_changed: bool,
+    keep_alive: KeepAlive,
+    offline_queue: Option<OfflineQueue>,
+    pending: Vec<(u16, Vec<SubscribePayload>)>,
+    incoming: VecDeque<u8>,
+    outgoing: Vec<u8>,
+    events: VecDeque<Event>,
+}
+
+impl Protocol {
+    ///Creates a new Protocol instance from the connect options, buffering publishes made while disconnected in the given queue.
+    pub fn new(options: &ConnectOptions, offline_queue: Option<OfflineQueue>) -> Self {
+        Self {
+            connect: options.connect_packet(),
+            clean_session: options.clean_session,
+            max_in_flight: options.max_in_flight,
+            max_packet_size: options.max_packet_size,
+            state: State::Disconnected,
+            session_present: false,
+            packet_ids: PacketIdAllocator::new(),
+            session: Session::new(),
+            session_changed: false,
+            keep_alive: KeepAlive::new(options.keep_alive, Instant::now()),
+            offline_queue,
+            pending: Vec::new(),
+            incoming: VecDeque::new(),
+            outgoing: Vec::new(),
+            events: VecDeque::new(),
+        }
+    }
+    ///Restores a session loaded from a session store, keeping the packet ids of its messages in flight reserved.
+    ///The active subscriptions are merged into it.
+    pub fn restore_session(&mut self, mut session: Session) {
+        for pid in session.outbound_ids() {
+            self.packet_ids.reserve(pid);
+        }
+        for subscription in self.session.subscriptions() {
+            session.add_subscription(subscription.topic_filter(), subscription.qos());
+        }
+        self.session = session;
+        self.session_changed = true;
+    }
+    ///Returns the session state.
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+    ///Returns true if the session state changed since the last call, and should be saved to the session store.
+    pub fn session_changed(&mut self) -> bool {
+        std::mem::take(&mut self.session_changed)
+    }
+    ///Returns the packet ids in use.
+    pub fn packet_ids(&self) -> &PacketIdAllocator {
+        &self.packet_ids
+    }
+    ///Returns true once the server accepted the connection, until it is lost.
+    pub fn is_connected(&self) -> bool {
+        self.state == State::Connected
+    }
+    ///Returns true if the server resumed a stored session on the last connect.
+    pub fn session_present(&self) -> bool {
+        self.session_present
+    }
+    ///Starts a connection over a freshly opened transport by sending CONNECT.
+    pub fn connect(&mut self, now: Instant) -> crate::types::error::Result<()> {
+        self.connection_lost();
+        let connect = self.connect.clone();
+        self.queue(&connect)?;
+        self.state = State::Connecting;
+        self.keep_alive.reset(now);
+        Ok(())
+    }
+    ///Forgets the current connection, discarding the bytes not yet read or taken.
+    ///The packet ids of subscribes and unsubscribes still waiting for their acknowledgement are released.
+    pub fn connection_lost(&mut self) {
+        self.state = State::Disconnected;
+        self.incoming.clear();
+        self.outgoing.clear();
+        for (pid, _) in self.pending.drain(..) {
+            self.packet_ids.release(pid);
+        }
+    }
+    ///Takes the bytes to write to the server, if there are any.
+    pub fn poll_transmit(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if self.outgoing.is_empty() {
+            return None;
+        }
+        self.keep_alive.on_send(now);
+        Some(std::mem::take(&mut self.outgoing))
+    }
+    ///Takes the next application event, if there is one.
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+    ///Returns how long until `handle_timeout` must be called, or None if the keep-alive is disabled or there is no connection.
+    pub fn timeout(&self, now: Instant) -> Option<Duration> {
+        match self.state {
+            State::Connected => self.keep_alive.timeout(now),
+            _ => None,
+        }
+    }
+    ///Sends PINGREQ when the link has been idle for the keep-alive interval.
+    ///Fails if an earlier PINGREQ went unanswered and the connection should be treated as dead.
+    pub fn handle_timeout(&mut self, now: Instant) -> crate::types::error::Result<()> {
+        if self.state != State::Connected {
+            return Ok(());
+        }
+        match self.keep_alive.poll(now) {
+            KeepAliveState::Idle => Ok(()),
+            KeepAliveState::Ping => {
+                let packet = ControlPacket::new(Header::new(header::FixedHeader::Pingreq, None), Payload { content: None });
+                self.queue(&packet)?;
+                self.keep_alive.on_ping(now);
+                Ok(())
+            }
+            KeepAliveState::Expired => Err(crate::types::error::Error::TimeoutError),
+        }
+    }
+    ///Handles bytes read from the server, answering the packets they complete and raising their events.
+    ///Fails if the server refused the connection or broke the protocol, in which case the connection should be dropped.
+    pub fn handle_incoming(&mut self, bytes: &[u8]) -> crate::types::error::Result<()> {
+        self.incoming.extend(bytes);
+        loop {
+            self.check_incoming_size()?;
+            let Some(packet) = ControlPacket::from_bytes(&mut self.incoming)? else {
+                return Ok(());
+            };
+            if self.state == State::Connecting {
+                self.handle_connack(packet)?;
+            } else {
+                self.handle_packet(packet)?;
+            }
+        }
+    }
+    ///Publishes a message to a topic with a specified QoS and retain flag.
+    ///Returns the packet id of the publication, or 0 for QoS 0 which carries none.
+    ///
+    ///While disconnected the publication is queued in the offline queue, and fails if there is none.
+    ///QoS 1 and QoS 2 publications waiting in the offline queue count towards the in-flight limit.
+    pub fn publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QOS,
+        retain: bool,
+    ) -> crate::types::error::Result<i32> {
+        let queued = self.offline_queue.as_ref().map_or(0, OfflineQueue::in_flight);
+        let pid = match qos {
+            QOS::Zero => None,
+            _ if self
+                .max_in_flight
+                .is_some_and(|max| self.session.outbound_ids().len() + queued >= max) =>
+            {
+                return Err(crate::types::error::Error::InFlightLimitExceededError)
+            }
+            _ => Some(self.packet_ids.allocate()?),
+        };
+        let packet = ControlPacket::new(
+            Header::new(
+                header::FixedHeader::Publish(false, qos, retain),
+                Some(VariableHeader::Publish(header::Publish {
+                    topic_name: EncodedString::new(topic),
+                    packet_id: pid.map(Integer::new),
+                })),
+            ),
+            Payload {
+                content: Some(payload::Payloads::Publish(payload.to_vec())),
+            },
+        );
+        let res = packet.to_bytes().and_then(|bytes| self.check_packet_size(&bytes));
+        let res = match (res, self.offline_queue.as_mut()) {
+            (Err(e), _) => Err(e),
+            (Ok(()), Some(queue)) if self.state != State::Connected => match queue.push(pid, packet) {
+                Ok(dropped) => {
+                    for pid in dropped.into_iter().flatten() {
+                        self.packet_ids.release(pid);
+                    }
+                    return Ok(pid.map_or(0, i32::from));
+                }
+                Err(e) => Err(e),
+            },
+            (Ok(()), None) if self.state != State::Connected => Err(crate::types::error::Error::RequestError),
+            (Ok(()), _) => self.queue_publish(pid, &packet),
+        };
+        match res {
+            Ok(()) => Ok(pid.map_or(0, i32::from)),
+            Err(e) => {
+                if let Some(pid) = pid {
+                    self.packet_ids.release(pid);
+                }
+                Err(e)
+            }
+        }
+    }
+    ///Drops a QoS 1 or QoS 2 publication from the session and releases its packet id,
+    ///for instance because it could not be saved to the session store.
+    pub fn discard(&mut self, packet_id: u16) {
+        if self.session.remove(packet_id).is_some() {
+            self.session_changed = true;
+        }
+        self.packet_ids.release(packet_id);
+    }
+    ///Subscribes to a topic with a specified QoS. Returns the packet id of the SUBSCRIBE.
+    ///The subscription becomes active once the server grants it in its SUBACK.
+    pub fn subscribe(&mut self, topic: &str, qos: QOS) -> crate::types::error::Result<u16> {
+        self.check_connected()?;
+        let pid = self.packet_ids.allocate()?;
+        let packet = subscribe_packet(pid, vec![SubscribePayload::new(topic, qos)]);
+        if let Err(e) = self.queue(&packet) {
+            self.packet_ids.release(pid);
+            return Err(e);
+        }
+        self.pending.push((pid, vec![SubscribePayload::new(topic, qos)]));
+        Ok(pid)
+    }
+    ///Returns the active subscriptions, which are replayed after a reconnect that did not resume the session.
+    pub fn subscriptions(&self) -> &[SubscribePayload] {
+        self.session.subscriptions()
+    }
+    ///Unsubscribes from a topic. Returns the packet id of the UNSUBSCRIBE.
+    pub fn unsubscribe(&mut self, topic: &str) -> crate::types::error::Result<u16> {
+        self.check_connected()?;
+        let pid = self.packet_ids.allocate()?;
+        let packet = ControlPacket::new(
+            Header::new(
+                header::FixedHeader::Unsubscribe,
+                Some(VariableHeader::Unsubscribe(header::Unsubscribe {
+                    packet_id: Integer::new(pid),
+                })),
+            ),
+            Payload {
+                content: Some(payload::Payloads::Unsubscribe(vec![EncodedString::new(topic)])),
+            },
+        );
+        if let Err(e) = self.queue(&packet) {
+            self.packet_ids.release(pid);
+            return Err(e);
+        }
+        self.pending.push((pid, Vec::new()));
+        self.session.remove_subscription(topic);
+        self.session_changed = true;
+        Ok(pid)
+    }
+    ///Disconnects from the server by sending DISCONNECT.
+    pub fn disconnect(&mut self) -> crate::types::error::Result<()> {
+        self.check_connected()?;
+        let packet = ControlPacket::new(Header::new(header::FixedHeader::Disconnect, None), Payload { content: None });
+        self.queue(&packet)?;
+        self.state = State::Disconnected;
+        self.events.push_back(Event::Disconnected);
+        Ok(())
+    }
+    ///Fails unless the server accepted the connection.
+    fn check_connected(&self) -> crate::types::error::Result<()> {
+        match self.state {
+            State::Connected => Ok(()),
+            _ => Err(crate::types::error::Error::RequestError),
+        }
+    }
+    ///Fails if the encoded packet is larger than the maximum packet size.
+    fn check_packet_size(&self, bytes: &[u8]) -> crate::types::error::Result<()> {
+        match self.max_packet_size {
+            Some(max) if bytes.len() > max => Err(crate::types::error::Error::PacketSizeExceededError),
+            _ => Ok(()),
+        }
+    }
+    ///Fails if the remaining length of the next incoming packet makes it larger than the maximum packet size,
+    ///before the packet is buffered any further or decoded.
+    fn check_incoming_size(&self) -> crate::types::error::Result<()> {
+        let Some(max) = self.max_packet_size else {
+            return Ok(());
+        };
+        match VariableByteInteger::from_bytes(self.incoming.iter().skip(1))? {
+            Some((len, len_bytes)) if 1 + len_bytes + len.to_u32() as usize > max => {
+                Err(crate::types::error::Error::PacketSizeExceededError)
+            }
+            _ => Ok(()),
+        }
+    }
+    ///Encodes a packet onto the bytes to write to the server.
+    fn queue(&mut self, packet: &ControlPacket) -> crate::types::error::Result<()> {
+        let bytes = packet.to_bytes()?;
+        self.check_packet_size(&bytes)?;
+        self.outgoing.extend(bytes);
+        Ok(())
+    }
+    ///Encodes a PUBLISH packet, storing it in the session first if it carries a packet id.
+    fn queue_publish(&mut self, packet_id: Option<u16>, packet: &ControlPacket) -> crate::types::error::Result<()> {
+        if let Some(pid) = packet_id {
+            self.session.store_publish(pid, packet.clone());
+            self.session_changed = true;
+        }
+        let res = self.queue(packet);
+        if let (Err(_), Some(pid)) = (&res, packet_id) {
+            self.session.remove(pid);
+        }
+        res
+    }
+    ///Handles the CONNACK answering CONNECT, failing if it is not one or if it refuses the connection.
+    ///
+    ///If the server resumed the session, unacknowledged PUBLISH and PUBREL packets are sent again in order.
+    ///Otherwise the messages in flight are discarded and the active subscriptions are replayed.
+    ///Publishes queued while disconnected are sent afterwards, in the order they were made.
+    fn handle_connack(&mut self, packet: ControlPacket) -> crate::types::error::Result<()> {
+        let Some(VariableHeader::Conack(connack)) = packet.header.variable else {
+            return Err(crate::types::error::Error::ConnectionError);
+        };
+        match connack.return_code() {
+            Some(header::ConnectReturnCode::Accepted) => {}
+            Some(code) => return Err(crate::types::error::Error::ConnectionRefusedError(code)),
+            None => return Err(crate::types::error::Error::MalformedPacketError),
+        }
+        self.state = State::Connected;
+        self.session_present = connack.session_present();
+        self.events.push_back(Event::Connected(self.session_present));
+        if !self.clean_session && self.session_present {
+            for packet in self.session.retransmissions() {
+                self.queue(&packet)?;
+            }
+        } else {
+            for pid in self.session.clear_in_flight() {
+                self.packet_ids.release(pid);
+            }
+            let subscriptions = self.session.subscriptions().to_vec();
+            if !subscriptions.is_empty() {
+                let pid = self.packet_ids.allocate()?;
+                self.queue(&subscribe_packet(pid, subscriptions.clone()))?;
+                self.pending.push((pid, subscriptions));
+            }
+        }
+        self.session_changed = true;
+        while let Some((pid, packet)) = self.offline_queue.as_mut().and_then(|queue| queue.pop()) {
+            if let Err(e) = self.queue_publish(pid, &packet) {
+                if let Some(queue) = self.offline_queue.as_mut() {
+                    queue.push_front(pid, packet);
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+    ///Handles a packet received once connected.
+    fn handle_packet(&mut self, packet: ControlPacket) -> crate::types::error::Result<()> {
+        match (packet.header.fixed, packet.header.variable.clone()) {
+            (header::FixedHeader::Suback, Some(VariableHeader::Suback(sub))) => {
+                let pid = sub.packet_id.to_u16();
+                let subscriptions = self.acknowledge_pending(pid);
+                let Some(payload::Payloads::SubAcknowledge(codes)) = packet.payload.content else {
+                    return Err(crate::types::error::Error::MalformedPacketError);
+                };
+                for (subscription, code) in subscriptions.iter().zip(codes) {
+                    match code {
+                        Ok(_) => self.session.add_subscription(subscription.topic_filter(), subscription.qos()),
+                        Err(_) => {
+                            self.session.remove_subscription(subscription.topic_filter());
+                        }
+                    }
+                    self.session_changed = true;
+                }
+                self.events.push_back(Event::Subscribed(pid));
+            }
+            (header::FixedHeader::Unsuback, Some(VariableHeader::Unsuback(unsub))) => {
+                let pid = unsub.packet_id.to_u16();
+                self.acknowledge_pending(pid);
+                self.events.push_back(Event::Unsubscribed(pid));
+            }
+            (header::FixedHeader::Puback, Some(VariableHeader::Puback(publ))) => {
+                let pid = publ.packet_id.to_u16();
+                if !self.session.acknowledge(pid) {
+                    return Ok(());
+                }
+                self.complete(pid);
+            }
+            (header::FixedHeader::Pubrec, Some(VariableHeader::Pubrec(publ))) => {
+                if !self.session.received(publ.packet_id.to_u16()) {
+                    return Ok(());
+                }
+                self.session_changed = true;
+                self.queue(&ControlPacket::acknowledgement(Acknowledgement::Pubrel, publ.packet_id))?;
+            }
+            (header::FixedHeader::Pubcomp, Some(VariableHeader::Pubcomp(publ))) => {
+                let pid = publ.packet_id.to_u16();
+                if !self.session.complete(pid) {
+                    return Ok(());
+                }
+                self.complete(pid);
+            }
+            (header::FixedHeader::Pubrel, Some(VariableHeader::Pubrel(publ))) => {
+                if self.session.release_inbound(publ.packet_id.to_u16()) {
+                    self.session_changed = true;
+                }
+                self.queue(&ControlPacket::acknowledgement(Acknowledgement::Pubcomp, publ.packet_id))?;
+            }
+            (header::FixedHeader::Publish(_, qos, _), _) => {
+                let Some(msg) = Message::from_packet(packet) else {
+                    return Ok(());
+                };
+                let pid = msg.packet_id().map(Integer::new);
+                let deliver = match (qos, pid) {
+                    (QOS::Two, Some(pid)) => self.session.store_inbound(pid.to_u16()),
+                    _ => true,
+                };
+                if deliver && qos == QOS::Two {
+                    self.session_changed = true;
+                }
+                if deliver {
+                    self.events.push_back(Event::Message(msg));
+                }
+                match (qos, pid) {
+                    (QOS::One, Some(pid)) => self.queue(&ControlPacket::acknowledgement(Acknowledgement::Puback, pid))?,
+                    (QOS::Two, Some(pid)) => self.queue(&ControlPacket::acknowledgement(Acknowledgement::Pubrec, pid))?,
+                    _ => {}
+                }
+            }
+            (header::FixedHeader::Pingresp, _) => self.keep_alive.on_pingresp(),
+            _ => {}
+        }
+        Ok(())
+    }
+    ///Releases the packet id of an acknowledged SUBSCRIBE or UNSUBSCRIBE, returning the subscriptions it requested.
+    ///An acknowledgement for a packet id not pending leaves the id alone, as it may belong to a publication.
+    fn acknowledge_pending(&mut self, packet_id: u16) -> Vec<SubscribePayload> {
+        match self.pending.iter().position(|(pid, _)| *pid == packet_id) {
+            Some(i) => {
+                self.packet_ids.release(packet_id);
+                self.pending.remove(i).1
+            }
+            None => Vec::new(),
+        }
+    }
+    ///Releases the packet id of a completed publication and raises its event.
+    fn complete(&mut self, packet_id: u16) {
+        self.session_changed = true;
+        self.packet_ids.release(packet_id);
+        self.events.push_back(Event::Published(packet_id));
+    }
+}
+
+///Builds a SUBSCRIBE packet for the given subscriptions.
+fn subscribe_packet(packet_id: u16, subscriptions: Vec<SubscribePayload>) -> ControlPacket {
+    ControlPacket::new(
+        Header::new(
+            header::FixedHeader::Subscribe,
+            Some(VariableHeader::Subscribe(header::Subscribe {
+                packet_id: Integer::new(packet_id),
+            })),
+        ),
+        Payload {
+            content: Some(payload::Payloads::Subscribe(subscriptions)),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::offline_queue::OverflowPolicy;
+
+    fn connected(uri: &str, offline_queue: Option<OfflineQueue>, connack: &[u8]) -> Protocol {
+        let options = ConnectOptions::new(uri).unwrap();
+        let mut protocol = Protocol::new(&options, offline_queue);
+        protocol.connect(Instant::now()).unwrap();
+        assert_eq!(
+            protocol.poll_transmit(Instant::now()),
+            Some(options.connect_packet().to_bytes().unwrap())
+        );
+        protocol.handle_incoming(connack).unwrap();
+        protocol
+    }
+
+    #[test]
+    fn connect_test() {
+        let mut protocol = connected("mqtt://localhost?client_id=id", None, &[0x20, 0x02]);
+        assert!(!protocol.is_connected());
+        protocol.handle_incoming(&[0x01, 0x00]).unwrap();
+        assert!(protocol.is_connected());
+        assert!(protocol.session_present());
+        assert_eq!(protocol.poll_event(), Some(Event::Connected(true)));
+        assert_eq!(protocol.poll_transmit(Instant::now()), None);
+        let options = ConnectOptions::new("mqtt://localhost").unwrap();
+        let mut protocol = Protocol::new(&options, None);
+        protocol.connect(Instant::now()).unwrap();
+        assert_eq!(
+            protocol.handle_incoming(&[0x20, 0x02, 0x00, 0x05]),
+            Err(crate::types::error::Error::ConnectionRefusedError(
+                header::ConnectReturnCode::NotAuthorized
+            ))
+        );
+    }
+    #[test]
+    fn qos_test() {
+        let mut protocol = connected("mqtt://localhost", None, &[0x20, 0x02, 0x00, 0x00]);
+        assert_eq!(protocol.publish("a", b"one", QOS::Two, false), Ok(1));
+        let publish = protocol.poll_transmit(Instant::now()).unwrap();
+        assert_eq!(publish[0], 0x34);
+        protocol.handle_incoming(&[0x50, 0x02, 0x00, 0x01]).unwrap();
+        assert_eq!(protocol.poll_transmit(Instant::now()), Some(vec![0x62, 0x02, 0x00, 0x01]));
+        protocol.handle_incoming(&[0x70, 0x02, 0x00, 0x01]).unwrap();
+        assert_eq!(protocol.poll_event(), Some(Event::Connected(false)));
+        assert_eq!(protocol.poll_event(), Some(Event::Published(1)));
+        assert!(!protocol.packet_ids().is_in_flight(1));
+        let publish = [0x34, 0x06, 0x00, 0x01, b'b', 0x00, 0x09, b'x'];
+        protocol.handle_incoming(&publish).unwrap();
+        protocol.handle_incoming(&publish).unwrap();
+        assert!(matches!(protocol.poll_event(), Some(Event::Message(msg)) if msg.topic() == "b"));
+        assert_eq!(protocol.poll_event(), None);
+        assert_eq!(
+            protocol.poll_transmit(Instant::now()),
+            Some([[0x50, 0x02, 0x00, 0x09], [0x50, 0x02, 0x00, 0x09]].concat())
+        );
+        protocol.handle_incoming(&[0x62, 0x02, 0x00, 0x09]).unwrap();
+        assert_eq!(protocol.poll_transmit(Instant::now()), Some(vec![0x70, 0x02, 0x00, 0x09]));
+        assert!(protocol.session().is_empty());
+    }
+    #[test]
+    fn keep_alive_test() {
+        let start = Instant::now();
+        let mut protocol = connected("mqtt://localhost?keep_alive=10", None, &[0x20, 0x02, 0x00, 0x00]);
+        protocol.keep_alive.reset(start);
+        assert_eq!(protocol.timeout(start), Some(Duration::from_secs(10)));
+        protocol.handle_timeout(start + Duration::from_secs(10)).unwrap();
+        assert_eq!(protocol.poll_transmit(start + Duration::from_secs(10)), Some(vec![0xc0, 0x00]));
+        protocol.handle_timeout(start + Duration::from_secs(15)).unwrap();
+        assert_eq!(protocol.poll_transmit(start + Duration::from_secs(15)), None);
+        assert_eq!(
+            protocol.handle_timeout(start + Duration::from_secs(20)),
+            Err(crate::types::error::Error::TimeoutError)
+        );
+        protocol.connection_lost();
+        assert_eq!(protocol.timeout(start), None);
+    }
+    #[test]
+    fn reconnect_test() {
+        let queue = OfflineQueue::new(4, 1024, OverflowPolicy::Reject);
+        let mut protocol = connected("mqtt://localhost?clean_session=false", Some(queue), &[0x20, 0x02, 0x00, 0x00]);
+        assert_eq!(protocol.subscribe("a/#", QOS::One), Ok(1));
+        assert_eq!(protocol.publish("a", b"one", QOS::One, false), Ok(2));
+        let mut sent = protocol.poll_transmit(Instant::now()).unwrap().into_iter().collect();
+        ControlPacket::from_bytes(&mut sent).unwrap();
+        let first = ControlPacket::from_bytes(&mut sent).unwrap().unwrap();
+        protocol.handle_incoming(&[0x90, 0x03, 0x00, 0x01, 0x01]).unwrap();
+        protocol.connection_lost();
+        assert!(!protocol.packet_ids().is_in_flight(1));
+        assert_eq!(protocol.publish("b", b"two", QOS::Zero, false), Ok(0));
+        assert_eq!(protocol.subscribe("b", QOS::Zero), Err(crate::types::error::Error::RequestError));
+        protocol.connect(Instant::now()).unwrap();
+        protocol.poll_transmit(Instant::now());
+        protocol.handle_incoming(&[0x20, 0x02, 0x01, 0x00]).unwrap();
+        let resent = protocol.poll_transmit(Instant::now()).unwrap();
+        let mut bytes = resent.iter().copied().collect();
+        let republish = ControlPacket::from_bytes(&mut bytes).unwrap().unwrap();
+        assert_eq!(republish.header.fixed(), header::FixedHeader::Publish(true, QOS::One, false));
+        assert_eq!(republish.header.variable, first.header.variable);
+        assert_eq!(republish.payload, first.payload);
+        let queued = ControlPacket::from_bytes(&mut bytes).unwrap().unwrap();
+        assert_eq!(queued.payload.content, Some(payload::Payloads::Publish(b"two".to_vec())));
+        assert!(bytes.is_empty());
+        protocol.connection_lost();
+        protocol.connect(Instant::now()).unwrap();
+        protocol.poll_transmit(Instant::now());
+        protocol.handle_incoming(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+        let resubscribe = protocol.poll_transmit(Instant::now()).unwrap();
+        assert_eq!(resubscribe, subscribe_packet(3, vec![SubscribePayload::new("a/#", QOS::One)]).to_bytes().unwrap());
+        assert!(!protocol.packet_ids().is_in_flight(2));
+    }
+    #[test]
+    fn suback_test() {
+        let mut protocol = connected("mqtt://localhost", None, &[0x20, 0x02, 0x00, 0x00]);
+        assert_eq!(protocol.subscribe("a/#", QOS::One), Ok(1));
+        assert_eq!(protocol.subscribe("b", QOS::Two), Ok(2));
+        assert!(protocol.subscriptions().is_empty());
+        protocol.handle_incoming(&[0x90, 0x03, 0x00, 0x01, 0x01]).unwrap();
+        protocol.handle_incoming(&[0x90, 0x03, 0x00, 0x02, 0x80]).unwrap();
+        assert_eq!(protocol.subscriptions(), [SubscribePayload::new("a/#", QOS::One)]);
+        assert_eq!(protocol.subscribe("a/#", QOS::Two), Ok(3));
+        protocol.handle_incoming(&[0x90, 0x03, 0x00, 0x03, 0x80]).unwrap();
+        assert!(protocol.subscriptions().is_empty());
+        assert_eq!(protocol.poll_event(), Some(Event::Connected(false)));
+        assert_eq!(protocol.poll_event(), Some(Event::Subscribed(1)));
+        assert_eq!(protocol.poll_event(), Some(Event::Subscribed(2)));
+        assert_eq!(protocol.poll_event(), Some(Event::Subscribed(3)));
+    }
+    #[test]
+    fn suback_test1() {
+        let mut protocol = connected("mqtt://localhost", None, &[0x20, 0x02, 0x00, 0x00]);
+        assert_eq!(protocol.publish("a", b"one", QOS::One, false), Ok(1));
+        protocol.handle_incoming(&[0x90, 0x03, 0x00, 0x01, 0x01]).unwrap();
+        protocol.handle_incoming(&[0xb0, 0x02, 0x00, 0x01]).unwrap();
+        assert!(protocol.packet_ids().is_in_flight(1));
+        assert!(protocol.subscriptions().is_empty());
+        assert_eq!(protocol.publish("a", b"two", QOS::One, false), Ok(2));
+    }
+    #[test]
+    fn max_packet_size_test() {
+        let mut protocol = connected("mqtt://localhost", None, &[0x20, 0x02, 0x00, 0x00]);
+        protocol.max_packet_size = Some(8);
+        protocol.handle_incoming(&[0x30, 0x06, 0x00, 0x01, b'a', b'h', b'i', b'!']).unwrap();
+        assert!(matches!(protocol.poll_event(), Some(Event::Connected(false))));
+        assert!(matches!(protocol.poll_event(), Some(Event::Message(_))));
+        let mut publish = vec![0x30, 0x0F, 0x00, 0x01, b'a'];
+        publish.extend([b'x'; 12]);
+        assert_eq!(
+            protocol.handle_incoming(&publish),
+            Err(crate::types::error::Error::PacketSizeExceededError)
+        );
+        assert_eq!(protocol.poll_event(), None);
+        let mut protocol = connected("mqtt://localhost", None, &[0x20, 0x02, 0x00, 0x00]);
+        protocol.max_packet_size = Some(8);
+        assert_eq!(
+            protocol.handle_incoming(&[0x30, 0xFF, 0xFF, 0x7F]),
+            Err(crate::types::error::Error::PacketSizeExceededError)
+        );
+    }
+    #[test]
+    fn max_in_flight_test() {
+        let options = ConnectOptions::new("mqtt://localhost").unwrap().max_in_flight(2);
+        let queue = OfflineQueue::new(4, 1024, OverflowPolicy::Reject);
+        let mut protocol = Protocol::new(&options, Some(queue));
+        assert_eq!(protocol.publish("a", b"one", QOS::One, false), Ok(1));
+        assert_eq!(protocol.publish("a", b"two", QOS::Zero, false), Ok(0));
+        assert_eq!(protocol.publish("a", b"three", QOS::Two, false), Ok(2));
+        assert_eq!(
+            protocol.publish("a", b"four", QOS::One, false),
+            Err(crate::types::error::Error::InFlightLimitExceededError)
+        );
+        protocol.connect(Instant::now()).unwrap();
+        protocol.poll_transmit(Instant::now());
+        protocol.handle_incoming(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+        assert_eq!(protocol.session().outbound_ids(), [1, 2]);
+        assert_eq!(
+            protocol.publish("a", b"four", QOS::One, false),
+            Err(crate::types::error::Error::InFlightLimitExceededError)
+        );
+    }
+}
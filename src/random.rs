@@ -0,0 +1,16 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+///Returns random bytes, seeded by the standard library's hasher keys.
+pub(crate) fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut res = [0_u8; N];
+    for chunk in res.chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(0);
+        let random = hasher.finish().to_le_bytes();
+        chunk.copy_from_slice(&random[..chunk.len()]);
+    }
+    res
+}
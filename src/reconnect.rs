@@ -0,0 +1,124 @@
+use std::time::Duration;
+
+use crate::{random::random_bytes, uri::invalid};
+
+///Represents how the client retries a lost connection: exponential backoff with jitter and an optional limit on attempts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectPolicy {
+    ///Delay before the second attempt. The first attempt is made right away.
+    pub initial_delay: Duration,
+    ///Upper bound for the delay between attempts.
+    pub max_delay: Duration,
+    ///Factor the delay grows by after each failed attempt, at least 1.
+    pub multiplier: f64,
+    ///Fraction of the delay, between 0 and 1, that is randomly taken off to spread out reconnecting clients.
+    pub jitter: f64,
+    ///Maximum number of attempts before giving up, or None to retry forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    ///Returns true if the given attempt, counting from 1, is allowed.
+    pub fn allows(&self, attempt: u32) -> bool {
+        self.max_attempts.is_none_or(|max| attempt <= max)
+    }
+    ///Fails with `Error::InvalidUriError` if the multiplier is below 1 or either the multiplier or the jitter is not finite.
+    pub(crate) fn validate(&self) -> crate::types::error::Result<()> {
+        if !self.multiplier.is_finite() || self.multiplier < 1.0 {
+            return Err(invalid(format!("invalid reconnect multiplier `{}`, expected at least 1", self.multiplier)));
+        }
+        if !self.jitter.is_finite() {
+            return Err(invalid(format!("invalid reconnect jitter `{}`, expected 0 to 1", self.jitter)));
+        }
+        Ok(())
+    }
+    ///Returns the delay to wait before the given attempt, counting from 1, with jitter applied.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.delay_with(attempt, random_fraction())
+    }
+    ///Returns the delay before the given attempt, taking `random` (between 0 and 1) of the jitter off.
+    fn delay_with(&self, attempt: u32, random: f64) -> Duration {
+        if attempt <= 1 {
+            return Duration::ZERO;
+        }
+        let exponent = (attempt - 2).min(i32::MAX as u32) as i32;
+        let delay = (self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max_delay.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0) * random.clamp(0.0, 1.0);
+        Duration::try_from_secs_f64(delay * (1.0 - jitter)).unwrap_or(self.max_delay)
+    }
+}
+
+///Returns a random number between 0 and 1.
+fn random_fraction() -> f64 {
+    (u64::from_le_bytes(random_bytes()) >> 11) as f64 / (1_u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_test() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_attempts: Some(5),
+        };
+        assert_eq!(policy.delay_with(1, 0.0), Duration::ZERO);
+        assert_eq!(policy.delay_with(2, 0.0), Duration::from_millis(100));
+        assert_eq!(policy.delay_with(3, 0.0), Duration::from_millis(200));
+        assert_eq!(policy.delay_with(4, 0.0), Duration::from_millis(400));
+        assert_eq!(policy.delay_with(5, 0.0), Duration::from_millis(500));
+        assert_eq!(policy.delay_with(u32::MAX, 0.0), Duration::from_millis(500));
+        assert_eq!(policy.delay_with(3, 1.0), Duration::from_millis(100));
+        assert!(policy.delay(3) <= Duration::from_millis(200));
+        assert!(policy.delay(3) >= Duration::from_millis(100));
+    }
+    #[test]
+    fn delay_test1() {
+        let policy = ReconnectPolicy {
+            max_attempts: Some(2),
+            ..Default::default()
+        };
+        assert!(policy.allows(2));
+        assert!(!policy.allows(3));
+        assert!(ReconnectPolicy::default().allows(u32::MAX));
+    }
+    #[test]
+    fn validate_test() {
+        assert_eq!(ReconnectPolicy::default().validate(), Ok(()));
+        for multiplier in [0.5, -2.0, f64::NAN, f64::INFINITY] {
+            let policy = ReconnectPolicy {
+                multiplier,
+                ..Default::default()
+            };
+            assert!(policy.validate().is_err());
+        }
+        let policy = ReconnectPolicy {
+            jitter: f64::NAN,
+            ..Default::default()
+        };
+        assert!(policy.validate().is_err());
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::ZERO,
+            multiplier: f64::MAX,
+            ..Default::default()
+        };
+        assert_eq!(policy.delay_with(u32::MAX, 0.0), policy.max_delay);
+    }
+}
@@ -0,0 +1,325 @@
+use crate::types::{
+    header::FixedHeader, payload::SubscribePayload, Acknowledgement, ControlPacket, Integer, Reader,
+    VariableByteInteger, QOS,
+};
+
+///Marks the start of a serialized session, followed by the format version.
+const MAGIC: &[u8; 5] = b"WIUX\x01";
+
+///Represents the state of an outbound QoS 1 or QoS 2 message that has not been fully acknowledged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutboundState {
+    ///The PUBLISH packet was sent and awaits PUBACK or PUBREC.
+    Publish(Box<ControlPacket>),
+    ///The PUBREL packet was sent and awaits PUBCOMP.
+    Pubrel,
+}
+
+///Represents the session state of a client: the outbound messages still in flight, in the order they were sent,
+///the packet ids of inbound QoS 2 messages awaiting PUBREL and the active subscriptions.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Session {
+    outbound: Vec<(u16, OutboundState)>,
+    inbound: Vec<u16>,
+    subscriptions: Vec<SubscribePayload>,
+}
+
+impl Session {
+    ///Creates a new, empty Session instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    ///Stores an outbound PUBLISH packet until it is acknowledged.
+    pub fn store_publish(&mut self, packet_id: u16, packet: ControlPacket) {
+        self.remove(packet_id);
+        self.outbound
+            .push((packet_id, OutboundState::Publish(Box::new(packet))));
+    }
+    ///Removes an outbound message, returning its state.
+    pub fn remove(&mut self, packet_id: u16) -> Option<OutboundState> {
+        let pos = self.outbound.iter().position(|(id, _)| *id == packet_id)?;
+        Some(self.outbound.remove(pos).1)
+    }
+    ///Handles PUBACK for a QoS 1 message. Returns true if the message was in flight.
+    pub fn acknowledge(&mut self, packet_id: u16) -> bool {
+        match self.state(packet_id) {
+            Some(OutboundState::Publish(_)) => self.remove(packet_id).is_some(),
+            _ => false,
+        }
+    }
+    ///Handles PUBREC for a QoS 2 message, moving it to the PUBREL state.
+    ///Returns true if the message is in flight and PUBREL should be sent.
+    pub fn received(&mut self, packet_id: u16) -> bool {
+        match self.outbound.iter_mut().find(|(id, _)| *id == packet_id) {
+            Some((_, state)) => {
+                *state = OutboundState::Pubrel;
+                true
+            }
+            None => false,
+        }
+    }
+    ///Handles PUBCOMP for a QoS 2 message. Returns true if the message was awaiting completion.
+    pub fn complete(&mut self, packet_id: u16) -> bool {
+        match self.state(packet_id) {
+            Some(OutboundState::Pubrel) => self.remove(packet_id).is_some(),
+            _ => false,
+        }
+    }
+    ///Records an inbound QoS 2 message until its PUBREL arrives.
+    ///Returns false if the packet id is already awaiting PUBREL, meaning the message is a duplicate.
+    pub fn store_inbound(&mut self, packet_id: u16) -> bool {
+        if self.inbound.contains(&packet_id) {
+            return false;
+        }
+        self.inbound.push(packet_id);
+        true
+    }
+    ///Handles PUBREL for an inbound QoS 2 message. Returns true if the packet id was awaiting PUBREL.
+    pub fn release_inbound(&mut self, packet_id: u16) -> bool {
+        let len = self.inbound.len();
+        self.inbound.retain(|id| *id != packet_id);
+        self.inbound.len() != len
+    }
+    ///Returns the packets to send again after a reconnect that resumed the session, in the order they were first sent.
+    ///PUBLISH packets get the DUP flag set.
+    pub fn retransmissions(&self) -> Vec<ControlPacket> {
+        self.outbound
+            .iter()
+            .map(|(id, state)| match state {
+                OutboundState::Publish(packet) => {
+                    let mut packet = packet.as_ref().clone();
+                    if let FixedHeader::Publish(_, qos, retain) = packet.header.fixed {
+                        packet.header.fixed = FixedHeader::Publish(true, qos, retain);
+                    }
+                    packet
+                }
+                OutboundState::Pubrel => {
+                    ControlPacket::acknowledgement(Acknowledgement::Pubrel, Integer::new(*id))
+                }
+            })
+            .collect()
+    }
+    ///Discards the outbound and inbound messages in flight, returning the outbound packet ids.
+    pub fn clear_in_flight(&mut self) -> Vec<u16> {
+        self.inbound.clear();
+        self.outbound.drain(..).map(|(id, _)| id).collect()
+    }
+    ///Records a subscription, replacing the QoS of an existing subscription to the same filter.
+    pub fn add_subscription(&mut self, topic_filter: &str, qos: QOS) {
+        match self
+            .subscriptions
+            .iter_mut()
+            .find(|s| s.topic_filter() == topic_filter)
+        {
+            Some(subscription) => subscription.qos = qos,
+            None => self
+                .subscriptions
+                .push(SubscribePayload::new(topic_filter, qos)),
+        }
+    }
+    ///Removes a subscription. Returns false if there was no subscription to the filter.
+    pub fn remove_subscription(&mut self, topic_filter: &str) -> bool {
+        let len = self.subscriptions.len();
+        self.subscriptions
+            .retain(|s| s.topic_filter() != topic_filter);
+        self.subscriptions.len() != len
+    }
+    ///Returns the active subscriptions, in the order they were made.
+    pub fn subscriptions(&self) -> &[SubscribePayload] {
+        &self.subscriptions
+    }
+    ///Returns true if no messages are in flight and there are no subscriptions.
+    pub fn is_empty(&self) -> bool {
+        self.outbound.is_empty() && self.inbound.is_empty() && self.subscriptions.is_empty()
+    }
+    ///Returns the packet ids of the outbound messages in flight, in the order they were sent.
+    pub fn outbound_ids(&self) -> Vec<u16> {
+        self.outbound.iter().map(|(id, _)| *id).collect()
+    }
+    ///Converts the Session instance to a byte vector.
+    pub fn to_bytes(&self) -> crate::types::error::Result<Vec<u8>> {
+        let mut res = MAGIC.to_vec();
+        res.extend(VariableByteInteger::new(self.outbound.len())?.to_bytes());
+        for (id, state) in &self.outbound {
+            res.extend(Integer::new(*id).to_bytes());
+            match state {
+                OutboundState::Publish(packet) => {
+                    res.push(0);
+                    res.extend(packet.to_bytes()?);
+                }
+                OutboundState::Pubrel => res.push(1),
+            }
+        }
+        res.extend(VariableByteInteger::new(self.inbound.len())?.to_bytes());
+        for id in &self.inbound {
+            res.extend(Integer::new(*id).to_bytes());
+        }
+        res.extend(VariableByteInteger::new(self.subscriptions.len())?.to_bytes());
+        for subscription in &self.subscriptions {
+            res.extend(subscription.to_bytes());
+        }
+        Ok(res)
+    }
+    ///Creates a new Session instance from a byte vector produced by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> crate::types::error::Result<Self> {
+        let mut reader = Reader::new(bytes);
+        if reader.read_bytes(MAGIC.len())? != MAGIC {
+            return Err(crate::types::error::Error::SessionStoreError);
+        }
+        let mut session = Session::new();
+        for _ in 0..reader.read_variable_integer()?.to_u32() {
+            let id = reader.read_integer()?.to_u16();
+            let state = match reader.read_u8()? {
+                0 => OutboundState::Publish(Box::new(reader.read_packet()?)),
+                1 => OutboundState::Pubrel,
+                _ => return Err(crate::types::error::Error::SessionStoreError),
+            };
+            session.outbound.push((id, state));
+        }
+        for _ in 0..reader.read_variable_integer()?.to_u32() {
+            session.inbound.push(reader.read_integer()?.to_u16());
+        }
+        for _ in 0..reader.read_variable_integer()?.to_u32() {
+            let topic_filter = reader.read_string()?;
+            let qos = match reader.read_u8()? {
+                0 => QOS::Zero,
+                1 => QOS::One,
+                2 => QOS::Two,
+                _ => return Err(crate::types::error::Error::SessionStoreError),
+            };
+            session.add_subscription(&topic_filter.value, qos);
+        }
+        reader.finish()?;
+        Ok(session)
+    }
+    ///Returns the state of an outbound message.
+    pub fn state(&self, packet_id: u16) -> Option<&OutboundState> {
+        self.outbound
+            .iter()
+            .find(|(id, _)| *id == packet_id)
+            .map(|(_, state)| state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn qos1_test() {
+        let mut session = Session::new();
+        session.store_publish(1, ControlPacket::default());
+        assert!(!session.complete(1));
+        assert!(session.acknowledge(1));
+        assert!(!session.acknowledge(1));
+        assert_eq!(session.state(1), None);
+    }
+    #[test]
+    fn qos2_test() {
+        let mut session = Session::new();
+        session.store_publish(7, ControlPacket::default());
+        assert!(!session.complete(7));
+        assert!(session.received(7));
+        assert_eq!(session.state(7), Some(&OutboundState::Pubrel));
+        assert!(session.received(7));
+        assert!(!session.acknowledge(7));
+        assert!(session.complete(7));
+        assert!(!session.complete(7));
+        assert!(!session.received(7));
+    }
+    #[test]
+    fn inbound_qos2_test() {
+        let mut session = Session::new();
+        assert!(session.store_inbound(3));
+        assert!(!session.store_inbound(3));
+        assert!(session.release_inbound(3));
+        assert!(!session.release_inbound(3));
+        assert!(session.store_inbound(3));
+    }
+    #[test]
+    fn subscriptions_test() {
+        let mut session = Session::new();
+        session.add_subscription("a/#", QOS::Zero);
+        session.add_subscription("b/+", QOS::One);
+        session.add_subscription("a/#", QOS::Two);
+        assert_eq!(
+            session.subscriptions(),
+            [
+                SubscribePayload::new("a/#", QOS::Two),
+                SubscribePayload::new("b/+", QOS::One)
+            ]
+        );
+        assert!(session.remove_subscription("a/#"));
+        assert!(!session.remove_subscription("a/#"));
+        assert_eq!(session.subscriptions(), [SubscribePayload::new("b/+", QOS::One)]);
+    }
+    #[test]
+    fn retransmissions_test() {
+        let publish = |packet_id: u16| {
+            ControlPacket::new(
+                crate::types::header::Header::new(
+                    FixedHeader::Publish(false, QOS::One, true),
+                    Some(crate::types::header::VariableHeader::Publish(
+                        crate::types::header::Publish {
+                            topic_name: crate::types::EncodedString::new("t"),
+                            packet_id: Some(Integer::new(packet_id)),
+                        },
+                    )),
+                ),
+                crate::types::payload::Payload::default(),
+            )
+        };
+        let mut session = Session::new();
+        session.store_publish(4, publish(4));
+        session.store_publish(2, publish(2));
+        session.store_publish(9, publish(9));
+        session.received(2);
+        let packets = session.retransmissions();
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets[0].header.fixed, FixedHeader::Publish(true, QOS::One, true));
+        assert_eq!(packets[0].header.variable, publish(4).header.variable);
+        assert_eq!(
+            packets[1],
+            ControlPacket::acknowledgement(Acknowledgement::Pubrel, Integer::new(2))
+        );
+        assert_eq!(packets[2].header.variable, publish(9).header.variable);
+        session.store_inbound(5);
+        assert_eq!(session.clear_in_flight(), [4, 2, 9]);
+        assert!(session.retransmissions().is_empty());
+        assert!(session.store_inbound(5));
+    }
+    #[test]
+    fn serialize_test() {
+        let mut session = Session::new();
+        assert_eq!(Session::from_bytes(&session.to_bytes().unwrap()), Ok(Session::new()));
+        let publish = ControlPacket::new(
+            crate::types::header::Header::new(
+                FixedHeader::Publish(false, QOS::Two, false),
+                Some(crate::types::header::VariableHeader::Publish(
+                    crate::types::header::Publish {
+                        topic_name: crate::types::EncodedString::new("t"),
+                        packet_id: Some(Integer::new(1)),
+                    },
+                )),
+            ),
+            crate::types::payload::Payload {
+                content: Some(crate::types::payload::Payloads::Publish(vec![7; 300])),
+            },
+        );
+        session.store_publish(1, publish);
+        session.store_publish(2, ControlPacket::acknowledgement(Acknowledgement::Pubrel, Integer::new(2)));
+        session.received(2);
+        session.store_inbound(11);
+        session.add_subscription("a/#", QOS::One);
+        let bytes = session.to_bytes().unwrap();
+        assert_eq!(Session::from_bytes(&bytes), Ok(session));
+        assert_eq!(
+            Session::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(crate::types::error::Error::TruncatedPacketError)
+        );
+        assert_eq!(
+            Session::from_bytes(b"nope!"),
+            Err(crate::types::error::Error::SessionStoreError)
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    fmt::Debug,
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use crate::session::Session;
+
+///Persists the session state of a client, so a client rebuilt with the same client id and
+///`clean_session = false` resumes its messages in flight and subscriptions.
+pub trait SessionStore: Debug + Send + Sync {
+    ///Loads the stored session state, or an empty session if nothing was stored.
+    fn load(&mut self) -> crate::types::error::Result<Session>;
+    ///Replaces the stored session state.
+    fn save(&mut self, session: &Session) -> crate::types::error::Result<()>;
+}
+
+///Represents a session store kept in memory. Clones share the same state, so a clone outlives the client it was given to.
+#[derive(Debug, Default, Clone)]
+pub struct MemorySessionStore {
+    session: Arc<Mutex<Session>>,
+}
+
+impl MemorySessionStore {
+    ///Creates a new, empty MemorySessionStore instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&mut self) -> crate::types::error::Result<Session> {
+        Ok(self
+            .session
+            .lock()
+            .map_err(|_| crate::types::error::Error::SessionStoreError)?
+            .clone())
+    }
+    fn save(&mut self, session: &Session) -> crate::types::error::Result<()> {
+        *self
+            .session
+            .lock()
+            .map_err(|_| crate::types::error::Error::SessionStoreError)? = session.clone();
+        Ok(())
+    }
+}
+
+///Represents a session store kept in a file.
+///
+///The state is written to a temporary file next to the target, synced to disk and then renamed over the target,
+///so a crash leaves either the old or the new state behind, never a partial one.
+#[derive(Debug, Clone)]
+pub struct FileSessionStore {
+    path: PathBuf,
+}
+
+impl FileSessionStore {
+    ///Creates a new FileSessionStore instance storing the session at the given path.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+    ///Returns the path of the temporary file written before it replaces the target.
+    fn temp_path(&self) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        self.path.with_file_name(name)
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn load(&mut self) -> crate::types::error::Result<Session> {
+        match fs::read(&self.path) {
+            Ok(bytes) => Session::from_bytes(&bytes),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Session::new()),
+            Err(_) => Err(crate::types::error::Error::SessionStoreError),
+        }
+    }
+    fn save(&mut self, session: &Session) -> crate::types::error::Result<()> {
+        let bytes = session.to_bytes()?;
+        let temp_path = self.temp_path();
+        let write = || -> std::io::Result<()> {
+            let mut file = File::create(&temp_path)?;
+            file.write_all(&bytes)?;
+            file.sync_all()?;
+            fs::rename(&temp_path, &self.path)?;
+            if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                File::open(dir)?.sync_all()?;
+            }
+            Ok(())
+        };
+        write().map_err(|_| crate::types::error::Error::SessionStoreError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::QOS;
+
+    fn session() -> Session {
+        let mut session = Session::new();
+        session.add_subscription("a/+", QOS::Two);
+        session.store_inbound(3);
+        session
+    }
+
+    #[test]
+    fn memory_store_test() {
+        let store = MemorySessionStore::new();
+        let mut other = store.clone();
+        assert_eq!(other.load(), Ok(Session::new()));
+        let mut store: Box<dyn SessionStore> = Box::new(store);
+        store.save(&session()).unwrap();
+        drop(store);
+        assert_eq!(other.load(), Ok(session()));
+    }
+    #[test]
+    fn file_store_test() {
+        let path = std::env::temp_dir().join(format!("wiux-store-{}", std::process::id()));
+        let mut store = FileSessionStore::new(&path);
+        assert_eq!(store.load(), Ok(Session::new()));
+        store.save(&session()).unwrap();
+        assert!(!store.temp_path().exists());
+        assert_eq!(FileSessionStore::new(&path).load(), Ok(session()));
+        fs::write(&path, b"garbage").unwrap();
+        assert_eq!(
+            store.load(),
+            Err(crate::types::error::Error::SessionStoreError)
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,379 @@
+use std::{
+    fmt::{self, Debug},
+    io::{self, ErrorKind, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    AlertDescription, ClientConfig, ClientConnection, RootCertStore,
+};
+
+use crate::transport::{self, Transport};
+
+///Represents the TLS settings of an `mqtts://` or `wss://` connection: trusted CAs,
+///client certificate, server name and ALPN protocols.
+///
+///Without a CA bundle the server certificate is verified against the Mozilla root certificates.
+#[derive(Default)]
+pub struct TlsOptions {
+    ca_certificates: Vec<CertificateDer<'static>>,
+    client_certificate: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+    server_name: Option<String>,
+    alpn: Vec<Vec<u8>>,
+}
+
+impl Debug for TlsOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsOptions")
+            .field("ca_certificates", &self.ca_certificates.len())
+            .field("client_certificate", &self.client_certificate.is_some())
+            .field("server_name", &self.server_name)
+            .field("alpn", &self.alpn)
+            .finish()
+    }
+}
+
+impl TlsOptions {
+    ///Creates a new TlsOptions instance trusting the Mozilla root certificates.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    ///Trusts the CA certificates of a PEM bundle instead of the Mozilla root certificates.
+    ///Fails with `Error::CertificateError` if the bundle holds no certificate.
+    pub fn ca_certificates(mut self, pem: &[u8]) -> crate::types::error::Result<Self> {
+        let certificates = parse_certificates(pem)?;
+        self.ca_certificates.extend(certificates);
+        Ok(self)
+    }
+    ///Authenticates the client with a PEM certificate chain and its PEM private key.
+    ///Fails with `Error::CertificateError` if either can not be parsed.
+    pub fn client_certificate(mut self, chain_pem: &[u8], key_pem: &[u8]) -> crate::types::error::Result<Self> {
+        let chain = parse_certificates(chain_pem)?;
+        let key = PrivateKeyDer::from_pem_slice(key_pem)
+            .map_err(|e| certificate_error(format!("invalid client private key: {e}")))?;
+        self.client_certificate = Some((chain, key));
+        Ok(self)
+    }
+    ///Sets the server name sent with SNI and checked against the server certificate, instead of the host.
+    pub fn server_name(mut self, server_name: &str) -> Self {
+        self.server_name = Some(server_name.to_owned());
+        self
+    }
+    ///Sets the protocols offered with ALPN, in order of preference.
+    pub fn alpn(mut self, protocols: &[&str]) -> Self {
+        self.alpn = protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+        self
+    }
+    ///Builds the rustls configuration.
+    fn config(&self) -> crate::types::error::Result<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        if self.ca_certificates.is_empty() {
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        }
+        for certificate in &self.ca_certificates {
+            roots
+                .add(certificate.clone())
+                .map_err(|e| certificate_error(format!("invalid CA certificate: {e}")))?;
+        }
+        let builder = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|_| crate::types::error::Error::ConnectionError)?
+            .with_root_certificates(roots);
+        let mut config = match &self.client_certificate {
+            Some((chain, key)) => builder
+                .with_client_auth_cert(chain.clone(), key.clone_key())
+                .map_err(|e| certificate_error(format!("invalid client certificate: {e}")))?,
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = self.alpn.clone();
+        Ok(config)
+    }
+}
+
+///Represents a TLS session over a TCP stream.
+///
+///Reads pull raw bytes from the socket without holding the session lock, so a blocked read
+///does not hold up writes from other threads.
+pub struct TlsTransport {
+    stream: TcpStream,
+    connection: Mutex<ClientConnection>,
+}
+
+impl Debug for TlsTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsTransport")
+            .field("stream", &self.stream)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TlsTransport {
+    ///Creates a new TlsTransport instance over a connected TCP stream, completing the TLS handshake within the timeout.
+    ///Fails with `Error::CertificateError` if either side rejects the other's certificate.
+    pub fn new(
+        mut stream: TcpStream,
+        host: &str,
+        options: &TlsOptions,
+        timeout: Duration,
+    ) -> crate::types::error::Result<Self> {
+        let name = options.server_name.as_deref().unwrap_or(host);
+        let server_name = ServerName::try_from(name.to_owned())
+            .map_err(|_| certificate_error(format!("invalid server name `{name}`")))?;
+        let mut connection = ClientConnection::new(Arc::new(options.config()?), server_name)
+            .map_err(|_| crate::types::error::Error::ConnectionError)?;
+        stream
+            .set_read_timeout(Some(timeout))
+            .map_err(|_| crate::types::error::Error::ConnectionError)?;
+        while connection.is_handshaking() {
+            connection
+                .complete_io(&mut stream)
+                .map_err(transport::connection_error)?;
+        }
+        let _ = stream.set_read_timeout(None);
+        Ok(Self {
+            stream,
+            connection: Mutex::new(connection),
+        })
+    }
+    ///Returns the protocol the server picked with ALPN, if any.
+    pub fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.connection().alpn_protocol().map(|p| p.to_vec())
+    }
+    ///Locks the TLS session.
+    fn connection(&self) -> std::sync::MutexGuard<'_, ClientConnection> {
+        self.connection
+            .lock()
+            .expect("FATAL: TLS connection lock poisoned")
+    }
+    ///Writes the TLS records waiting in the session to the socket.
+    fn flush(&self, connection: &mut ClientConnection) -> io::Result<()> {
+        while connection.wants_write() {
+            connection.write_tls(&mut &self.stream)?;
+        }
+        Ok(())
+    }
+}
+
+impl Transport for TlsTransport {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut connection = self.connection();
+                match connection.reader().read(buf) {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    res => return res,
+                }
+                self.flush(&mut connection)?;
+            }
+            let mut raw = [0_u8; 4096];
+            let n = Read::read(&mut &self.stream, &mut raw)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let mut connection = self.connection();
+            let mut raw = &raw[..n];
+            while !raw.is_empty() {
+                connection.read_tls(&mut raw)?;
+                connection
+                    .process_new_packets()
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            }
+        }
+    }
+    fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        let mut connection = self.connection();
+        connection.writer().write_all(buf)?;
+        self.flush(&mut connection)
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        let mut connection = self.connection();
+        connection.send_close_notify();
+        let _ = self.flush(&mut connection);
+        self.stream.shutdown(Shutdown::Both)
+    }
+}
+
+///Parses every certificate of a PEM bundle, failing if it holds none.
+fn parse_certificates(pem: &[u8]) -> crate::types::error::Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_slice_iter(pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| certificate_error(format!("invalid PEM certificate: {e}")))?;
+    if certificates.is_empty() {
+        return Err(certificate_error("no certificate in PEM bundle".to_owned()));
+    }
+    Ok(certificates)
+}
+
+///Creates the error returned when a certificate is invalid or rejected.
+fn certificate_error(reason: String) -> crate::types::error::Error {
+    crate::types::error::Error::CertificateError(reason)
+}
+
+///Returns `Error::CertificateError` if the I/O error comes from either side of a TLS session rejecting a certificate.
+pub(crate) fn rejected_certificate(e: &io::Error) -> Option<crate::types::error::Error> {
+    match e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()) {
+        Some(
+            e @ (rustls::Error::InvalidCertificate(_)
+            | rustls::Error::NoCertificatesPresented
+            | rustls::Error::AlertReceived(
+                AlertDescription::BadCertificate
+                | AlertDescription::UnsupportedCertificate
+                | AlertDescription::CertificateRevoked
+                | AlertDescription::CertificateExpired
+                | AlertDescription::CertificateUnknown
+                | AlertDescription::UnknownCA
+                | AlertDescription::CertificateRequired,
+            )),
+        ) => Some(certificate_error(e.to_string())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        net::TcpListener,
+        thread,
+    };
+
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::{server::WebPkiClientVerifier, ServerConfig, ServerConnection, StreamOwned};
+
+    use super::*;
+    use crate::{options::ConnectOptions, types::ControlPacket};
+
+    ///Represents a self-signed CA with a server certificate for `localhost` and a client certificate.
+    struct Certificates {
+        ca: rcgen::Certificate,
+        server: rcgen::Certificate,
+        server_key: KeyPair,
+        client: rcgen::Certificate,
+        client_key: KeyPair,
+    }
+
+    fn certificates() -> Certificates {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let server_key = KeyPair::generate().unwrap();
+        let server = CertificateParams::new(vec!["localhost".to_owned()])
+            .unwrap()
+            .signed_by(&server_key, &ca, &ca_key)
+            .unwrap();
+        let client_key = KeyPair::generate().unwrap();
+        let client = CertificateParams::new(vec!["client".to_owned()])
+            .unwrap()
+            .signed_by(&client_key, &ca, &ca_key)
+            .unwrap();
+        Certificates {
+            ca,
+            server,
+            server_key,
+            client,
+            client_key,
+        }
+    }
+
+    fn server_config(certificates: &Certificates) -> ServerConfig {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut roots = RootCertStore::empty();
+        roots.add(certificates.ca.der().clone()).unwrap();
+        let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+            .build()
+            .unwrap();
+        let key = PrivateKeyDer::from_pem_slice(certificates.server_key.serialize_pem().as_bytes()).unwrap();
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(vec![certificates.server.der().clone()], key)
+            .unwrap();
+        config.alpn_protocols = vec![b"mqtt".to_vec()];
+        config
+    }
+
+    fn read_packet(stream: &mut StreamOwned<ServerConnection, TcpStream>, bytes: &mut VecDeque<u8>) -> ControlPacket {
+        loop {
+            if let Some(packet) = ControlPacket::from_bytes(bytes).unwrap() {
+                return packet;
+            }
+            let mut buf = [0_u8; 256];
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0);
+            bytes.extend(&buf[..n]);
+        }
+    }
+
+    #[test]
+    fn mutual_tls_test() {
+        let certificates = certificates();
+        let config = Arc::new(server_config(&certificates));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = StreamOwned::new(ServerConnection::new(config).unwrap(), stream);
+            let mut bytes = VecDeque::new();
+            read_packet(&mut stream, &mut bytes);
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+            let publish = read_packet(&mut stream, &mut bytes);
+            (stream.conn.alpn_protocol().map(|p| p.to_vec()), publish)
+        });
+        let tls = TlsOptions::new()
+            .ca_certificates(certificates.ca.pem().as_bytes())
+            .unwrap()
+            .client_certificate(
+                certificates.client.pem().as_bytes(),
+                certificates.client_key.serialize_pem().as_bytes(),
+            )
+            .unwrap()
+            .server_name("localhost")
+            .alpn(&["mqtt"]);
+        let client = ConnectOptions::new(&format!("mqtts://127.0.0.1:{port}?client_id=id"))
+            .unwrap()
+            .tls(tls)
+            .connect()
+            .unwrap();
+        client.publish("a", "secret", crate::types::QOS::Zero, false).unwrap();
+        let (alpn, publish) = handle.join().unwrap();
+        assert_eq!(alpn, Some(b"mqtt".to_vec()));
+        assert_eq!(
+            publish.payload().content,
+            Some(crate::types::payload::Payloads::Publish(b"secret".to_vec()))
+        );
+    }
+    #[test]
+    fn certificate_test() {
+        let certificates = certificates();
+        let config = Arc::new(server_config(&certificates));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut connection = ServerConnection::new(config).unwrap();
+            while connection.is_handshaking() && connection.complete_io(&mut stream).is_ok() {}
+        });
+        let res = ConnectOptions::new(&format!("mqtts://localhost:{port}?client_id=id"))
+            .unwrap()
+            .connect();
+        handle.join().unwrap();
+        assert!(
+            matches!(res, Err(crate::types::error::Error::CertificateError(_))),
+            "{res:?}"
+        );
+        assert_eq!(
+            TlsOptions::new().ca_certificates(b"not a certificate").err(),
+            Some(crate::types::error::Error::CertificateError(
+                "no certificate in PEM bundle".to_owned()
+            ))
+        );
+    }
+}
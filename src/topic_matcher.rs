@@ -66,6 +66,7 @@ impl TopicMatcher {
     ///let msg_topic = "one/two/some/another/twonother";
     ///assert!(matcher.matches(msg_topic));
     ///```
+    #[allow(clippy::doc_lazy_continuation)]
     pub fn matches(&self, msg_topic: &str) -> bool {
         for zipped in zip_longest(self.topic_filter.split('/'), msg_topic.split('/')) {
             match zipped {
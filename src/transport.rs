@@ -0,0 +1,295 @@
+use std::{
+    fmt::Debug,
+    io::{self, ErrorKind, Read, Write},
+    net::{Shutdown, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::{os::unix::net::UnixStream, path::Path};
+
+#[cfg(feature = "tls")]
+use crate::tls::{TlsOptions, TlsTransport};
+#[cfg(feature = "websocket")]
+use crate::websocket::{WebSocketOptions, WebSocketTransport};
+use crate::options::{ConnectOptions, TransportKind};
+
+///Represents a connected byte stream the client speaks MQTT over.
+///
+///Every method takes `&self`: the transport is split between the loop reading from it
+///and the threads publishing through it, so a read and a write may run at the same time.
+pub trait Transport: Debug + Send + Sync {
+    ///Reads bytes into the buffer, returning 0 once the stream is closed.
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize>;
+    ///Writes the whole buffer to the stream.
+    fn write_all(&self, buf: &[u8]) -> io::Result<()>;
+    ///Sets how long a read may block before failing with `WouldBlock` or `TimedOut`, None blocks indefinitely.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    ///Shuts down both directions of the stream, waking up a blocked read.
+    fn shutdown(&self) -> io::Result<()>;
+    ///Reads exactly enough bytes to fill the buffer.
+    fn read_exact(&self, mut buf: &mut [u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read(buf) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(n) => buf = &mut buf[n..],
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transport for TcpStream {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(&mut &*self, buf)
+    }
+    fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        Write::write_all(&mut &*self, buf)
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(&mut &*self, buf)
+    }
+    fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        Write::write_all(&mut &*self, buf)
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+///Opens a stream to the server over the transport picked from the connection URI.
+pub(crate) fn connect(options: &ConnectOptions) -> crate::types::error::Result<Box<dyn Transport>> {
+    match options.transport() {
+        TransportKind::Tcp => Ok(Box::new(connect_tcp(options)?)),
+        #[cfg(feature = "tls")]
+        TransportKind::Tls => Ok(Box::new(connect_tls(options)?)),
+        #[cfg(feature = "websocket")]
+        TransportKind::WebSocket { path } => {
+            Ok(Box::new(connect_websocket(Box::new(connect_tcp(options)?), path, options)?))
+        }
+        #[cfg(all(feature = "tls", feature = "websocket"))]
+        TransportKind::SecureWebSocket { path } => {
+            Ok(Box::new(connect_websocket(Box::new(connect_tls(options)?), path, options)?))
+        }
+        #[cfg(unix)]
+        TransportKind::Unix { path } => Ok(Box::new(connect_unix(path)?)),
+        #[allow(unreachable_patterns)]
+        _ => Err(crate::types::error::Error::UnsupportedTransportError),
+    }
+}
+
+///Connects to a Unix domain socket. A path starting with `@` names a socket in the Linux abstract namespace.
+#[cfg(unix)]
+fn connect_unix(path: &Path) -> crate::types::error::Result<UnixStream> {
+    let res = match path.to_str().and_then(|p| p.strip_prefix('@')) {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        Some(name) => {
+            #[cfg(target_os = "android")]
+            use std::os::android::net::SocketAddrExt;
+            #[cfg(target_os = "linux")]
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(name)
+                .and_then(|addr| UnixStream::connect_addr(&addr))
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        Some(_) => return Err(crate::types::error::Error::UnsupportedTransportError),
+        None => UnixStream::connect(path),
+    };
+    res.map_err(|e| unix_error(e, path))
+}
+
+///Maps a failed connect to a Unix domain socket to `Error::SocketNotFoundError` if nothing listens at the path,
+///`Error::PermissionDeniedError` if the socket may not be opened, or `Error::ConnectionError`.
+#[cfg(unix)]
+fn unix_error(e: io::Error, path: &Path) -> crate::types::error::Error {
+    let path = path.display().to_string();
+    match e.kind() {
+        ErrorKind::NotFound | ErrorKind::ConnectionRefused => {
+            crate::types::error::Error::SocketNotFoundError(path)
+        }
+        ErrorKind::PermissionDenied => crate::types::error::Error::PermissionDeniedError(path),
+        _ => crate::types::error::Error::ConnectionError,
+    }
+}
+
+///Opens a TLS session over TCP to the host and port of the connection URI.
+#[cfg(feature = "tls")]
+fn connect_tls(options: &ConnectOptions) -> crate::types::error::Result<TlsTransport> {
+    let default = TlsOptions::default();
+    let tls = options.tls.as_ref().unwrap_or(&default);
+    TlsTransport::new(connect_tcp(options)?, options.host(), tls, options.connect_timeout)
+}
+
+///Opens a WebSocket to the given path over a connected transport.
+#[cfg(feature = "websocket")]
+fn connect_websocket(
+    inner: Box<dyn Transport>,
+    path: &str,
+    options: &ConnectOptions,
+) -> crate::types::error::Result<WebSocketTransport> {
+    let default = WebSocketOptions::default();
+    let websocket = options.websocket.as_ref().unwrap_or(&default);
+    let host = crate::uri::authority(options.host(), options.port());
+    WebSocketTransport::new(inner, &host, path, websocket, options.connect_timeout)
+}
+
+///Opens a TCP stream to the host and port of the connection URI, tunnelled through the proxy if one is set.
+fn connect_tcp(options: &ConnectOptions) -> crate::types::error::Result<TcpStream> {
+    if let Some(proxy) = &options.proxy {
+        return proxy.connect(options.host(), options.port(), options.connect_timeout);
+    }
+    tcp_connect(options.host(), options.port(), options.connect_timeout)
+}
+
+///Opens a TCP stream to the host and port, trying each resolved address in turn and giving each the timeout.
+///Fails with `Error::TimeoutError` if the last address timed out, or `Error::ConnectionError`.
+pub(crate) fn tcp_connect(host: &str, port: u32, timeout: Duration) -> crate::types::error::Result<TcpStream> {
+    let addrs = crate::uri::authority(host, port)
+        .to_socket_addrs()
+        .map_err(|_| crate::types::error::Error::ConnectionError)?;
+    let mut res = Err(crate::types::error::Error::ConnectionError);
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => res = Err(connection_error(e)),
+        }
+    }
+    res
+}
+
+///Maps an I/O error of a transport to `Error::TimeoutError` if it timed out,
+///`Error::CertificateError` if a certificate was rejected, or `Error::ConnectionError`.
+pub(crate) fn connection_error(e: io::Error) -> crate::types::error::Error {
+    if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) {
+        return crate::types::error::Error::TimeoutError;
+    }
+    #[cfg(feature = "tls")]
+    if let Some(e) = crate::tls::rejected_certificate(&e) {
+        return e;
+    }
+    crate::types::error::Error::ConnectionError
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{
+        os::unix::net::UnixListener,
+        path::PathBuf,
+        thread,
+    };
+
+    use super::*;
+    use crate::types::{payload::Payloads, ControlPacket, QOS};
+
+    ///Accepts one client, answers its CONNECT and returns the packet it sends next.
+    fn broker<S: Transport + 'static>(accept: impl FnOnce() -> S + Send + 'static) -> thread::JoinHandle<ControlPacket> {
+        thread::spawn(move || {
+            let stream = accept();
+            let mut bytes = std::collections::VecDeque::new();
+            let mut read_packet = || loop {
+                if let Some(packet) = ControlPacket::from_bytes(&mut bytes).unwrap() {
+                    return packet;
+                }
+                let mut buf = [0_u8; 256];
+                let n = Transport::read(&stream, &mut buf).unwrap();
+                assert!(n > 0);
+                bytes.extend(&buf[..n]);
+            };
+            read_packet();
+            Transport::write_all(&stream, &[0x20, 0x02, 0x00, 0x00]).unwrap();
+            read_packet()
+        })
+    }
+
+    #[test]
+    fn unix_test() {
+        let path = std::env::temp_dir().join(format!("wiux-unix-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let handle = broker(move || listener.accept().unwrap().0);
+        let client = ConnectOptions::new(&format!("unix://{}?client_id=id", path.display()))
+            .unwrap()
+            .connect()
+            .unwrap();
+        client.publish("a", "local", QOS::Zero, false).unwrap();
+        let publish = handle.join().unwrap();
+        assert_eq!(publish.payload().content, Some(Payloads::Publish(b"local".to_vec())));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            connect_unix(&path).err(),
+            Some(crate::types::error::Error::SocketNotFoundError(path.display().to_string()))
+        );
+    }
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn unix_test1() {
+        use std::os::linux::net::SocketAddrExt;
+        let name = format!("wiux-unix-{}", std::process::id());
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+        let listener = UnixListener::bind_addr(&addr).unwrap();
+        let handle = broker(move || listener.accept().unwrap().0);
+        let client = ConnectOptions::new(&format!("unix://@{name}?client_id=id"))
+            .unwrap()
+            .connect()
+            .unwrap();
+        client.publish("a", "abstract", QOS::Zero, false).unwrap();
+        let publish = handle.join().unwrap();
+        assert_eq!(publish.payload().content, Some(Payloads::Publish(b"abstract".to_vec())));
+        assert_eq!(
+            connect_unix(Path::new(&format!("@{name}"))).err(),
+            Some(crate::types::error::Error::SocketNotFoundError(format!("@{name}")))
+        );
+    }
+    #[test]
+    fn ipv6_test() {
+        let listener = std::net::TcpListener::bind("[::1]:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = broker(move || listener.accept().unwrap().0);
+        let client = ConnectOptions::new(&format!("mqtt://[::1]:{port}?client_id=id"))
+            .unwrap()
+            .connect()
+            .unwrap();
+        assert_eq!(client.host(), "::1");
+        client.publish("a", "v6", QOS::Zero, false).unwrap();
+        let publish = handle.join().unwrap();
+        assert_eq!(publish.payload().content, Some(Payloads::Publish(b"v6".to_vec())));
+    }
+    #[test]
+    fn tcp_connect_test() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().into();
+        assert!(tcp_connect("127.0.0.1", port, Duration::from_secs(1)).is_ok());
+        drop(listener);
+        assert_eq!(
+            tcp_connect("127.0.0.1", port, Duration::from_secs(1)).err(),
+            Some(crate::types::error::Error::ConnectionError)
+        );
+    }
+    #[test]
+    fn unix_error_test() {
+        let path = PathBuf::from("/run/broker.sock");
+        assert_eq!(
+            unix_error(ErrorKind::PermissionDenied.into(), &path),
+            crate::types::error::Error::PermissionDeniedError("/run/broker.sock".to_owned())
+        );
+        assert_eq!(
+            unix_error(ErrorKind::NotFound.into(), &path),
+            crate::types::error::Error::SocketNotFoundError("/run/broker.sock".to_owned())
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use super::header::ConnectReturnCode;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -7,6 +9,26 @@ pub enum Error {
     InvalidTopicMatcherError(&'static str),
     PublicationError,
     ConnectionError,
+    ConnectionRefusedError(ConnectReturnCode),
+    TimeoutError,
+    RemainingLengthExceededError,
+    MalformedPacketError,
+    TruncatedPacketError,
+    InvalidReservedFlagsError(u8),
+    UnknownPacketTypeError(u8),
+    PacketIdExhaustedError,
+    SessionStoreError,
+    OfflineQueueFullError,
+    InFlightLimitExceededError,
+    PacketSizeExceededError,
+    InvalidUriError(String),
+    UnsupportedTransportError,
+    CertificateError(String),
+    WebSocketHandshakeError(String),
+    SocketNotFoundError(String),
+    PermissionDeniedError(String),
+    ProxyError(String),
+    LoopAlreadyStartedError,
     #[default]
     Default,
 }
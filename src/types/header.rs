@@ -1,4 +1,4 @@
-use super::{EncodedString, Integer, QOS};
+use super::{EncodedString, Integer, VariableByteInteger, QOS};
 
 ///Represents an MQTT header, consisting of a fixed header and an optional variable header.
 #[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -15,14 +15,24 @@ impl Header {
             variable: variable_header,
         }
     }
-    ///Converts the header to a byte vector.
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut res = Vec::new();
-        res.extend(self.fixed.to_bytes());
-        if let Some(v) = &self.variable {
-            res.extend(v.to_bytes());
-        }
-        res
+    ///Returns the fixed header.
+    pub fn fixed(&self) -> FixedHeader {
+        self.fixed
+    }
+    ///Returns the variable header, if the packet has one.
+    pub fn variable(&self) -> Option<&VariableHeader> {
+        self.variable.as_ref()
+    }
+    ///Converts the header to a byte vector, given the length of the payload following it.
+    pub fn to_bytes(&self, payload_length: usize) -> crate::types::error::Result<Vec<u8>> {
+        let variable = self
+            .variable
+            .as_ref()
+            .map(|v| v.to_bytes())
+            .unwrap_or_default();
+        let mut res = self.fixed.to_bytes(variable.len() + payload_length)?;
+        res.extend(variable);
+        Ok(res)
     }
 }
 
@@ -47,13 +57,49 @@ pub enum FixedHeader {
 }
 
 impl FixedHeader {
-    ///Converts the fixed header to a byte vector.
-    pub fn to_bytes(&self) -> Vec<u8> {
+    ///Creates a new FixedHeader instance from the first byte of a packet, validating its flags.
+    pub fn from_byte(byte: u8) -> crate::types::error::Result<Self> {
+        let flags = byte & 15;
+        let (fixed, expected_flags) = match byte >> 4 {
+            1 => (FixedHeader::Connect, 0),
+            2 => (FixedHeader::Connack, 0),
+            3 => {
+                let qos = match (flags >> 1) & 3 {
+                    0 => QOS::Zero,
+                    1 => QOS::One,
+                    2 => QOS::Two,
+                    _ => return Err(crate::types::error::Error::InvalidReservedFlagsError(byte)),
+                };
+                return Ok(FixedHeader::Publish(flags & 8 != 0, qos, flags & 1 != 0));
+            }
+            4 => (FixedHeader::Puback, 0),
+            5 => (FixedHeader::Pubrec, 0),
+            6 => (FixedHeader::Pubrel, 2),
+            7 => (FixedHeader::Pubcomp, 0),
+            8 => (FixedHeader::Subscribe, 2),
+            9 => (FixedHeader::Suback, 0),
+            10 => (FixedHeader::Unsubscribe, 2),
+            11 => (FixedHeader::Unsuback, 0),
+            12 => (FixedHeader::Pingreq, 0),
+            13 => (FixedHeader::Pingresp, 0),
+            14 => (FixedHeader::Disconnect, 0),
+            _ => return Err(crate::types::error::Error::UnknownPacketTypeError(byte)),
+        };
+        if flags != expected_flags {
+            return Err(crate::types::error::Error::InvalidReservedFlagsError(byte));
+        }
+        Ok(fixed)
+    }
+    ///Converts the fixed header to a byte vector, encoding the remaining length as a variable byte integer.
+    pub fn to_bytes(&self, remaining_length: usize) -> crate::types::error::Result<Vec<u8>> {
         let mut res = Vec::new();
         match self {
             FixedHeader::Connect => {
                 res.push(2_u8.pow(4));
             },
+            FixedHeader::Connack => {
+                res.push(2_u8.pow(5));
+            },
             FixedHeader::Publish(dup_flag, qos, retain_flag) => {
                 let mut byte1 = 2_u8.pow(5) + 2_u8.pow(4);
                 if *dup_flag {byte1 += 2_u8.pow(3)}
@@ -65,22 +111,42 @@ impl FixedHeader {
                 }
                 res.push(byte1);
             },
+            FixedHeader::Puback => {
+                res.push(2_u8.pow(6));
+            },
+            FixedHeader::Pubrec => {
+                res.push(2_u8.pow(6) + 2_u8.pow(4));
+            },
+            FixedHeader::Pubrel => {
+                res.push(2_u8.pow(6) + 2_u8.pow(5) + 2_u8.pow(1));
+            },
+            FixedHeader::Pubcomp => {
+                res.push(2_u8.pow(6) + 2_u8.pow(5) + 2_u8.pow(4));
+            },
             FixedHeader::Subscribe => {
                 res.push(2_u8.pow(7) + 2_u8.pow(1));
             },
+            FixedHeader::Suback => {
+                res.push(2_u8.pow(7) + 2_u8.pow(4));
+            },
             FixedHeader::Unsubscribe => {
                 res.push(2_u8.pow(7) + 2_u8.pow(5) + 2_u8.pow(1));
             },
+            FixedHeader::Unsuback => {
+                res.push(2_u8.pow(7) + 2_u8.pow(5) + 2_u8.pow(4));
+            },
             FixedHeader::Pingreq => {
                 res.push(2_u8.pow(7) + 2_u8.pow(6));
             },
+            FixedHeader::Pingresp => {
+                res.push(2_u8.pow(7) + 2_u8.pow(6) + 2_u8.pow(4));
+            },
             FixedHeader::Disconnect => {
-                res.push(2_u8.pow(6) + 2_u8.pow(5));
+                res.push(2_u8.pow(7) + 2_u8.pow(6) + 2_u8.pow(5));
             },
-            _ => {}
         }
-        res.push(0);
-        res
+        res.extend(VariableByteInteger::new(remaining_length)?.to_bytes());
+        Ok(res)
     }
 }
 
@@ -115,15 +181,33 @@ impl VariableHeader {
             },
             VariableHeader::Publish(h) => {
                 res.extend(h.topic_name.to_bytes());
+                if let Some(packet_id) = h.packet_id {
+                    res.extend(packet_id.to_bytes());
+                }
+            },
+            VariableHeader::Conack(h) => {
+                res.push(h.connect_acknowledge_flags);
+                res.push(h.connect_return_code);
+            },
+            VariableHeader::Puback(h) => {
                 res.extend(h.packet_id.to_bytes());
             },
-            VariableHeader::Subscribe(h) => {
+            VariableHeader::Pubrec(h) => {
                 res.extend(h.packet_id.to_bytes());
             },
-            VariableHeader::Unsubscribe(h) => {
+            VariableHeader::Pubrel(h) => {
                 res.extend(h.packet_id.to_bytes());
             },
-            _ => {},
+            VariableHeader::Pubcomp(h) => {
+                res.extend(h.packet_id.to_bytes());
+            },
+            VariableHeader::Subscribe(h) | VariableHeader::Suback(h) => {
+                res.extend(h.packet_id.to_bytes());
+            },
+            VariableHeader::Unsubscribe(h) | VariableHeader::Unsuback(h) => {
+                res.extend(h.packet_id.to_bytes());
+            },
+            VariableHeader::Default => {},
         }
         res
     }
@@ -145,11 +229,60 @@ pub struct ConnectAcknowledge {
     pub connect_return_code: u8,
 }
 
+impl ConnectAcknowledge {
+    ///Returns true if the server resumed a session stored from an earlier connection.
+    pub fn session_present(&self) -> bool {
+        self.connect_acknowledge_flags & 1 != 0
+    }
+    ///Returns the return code, or None if the code is reserved.
+    pub fn return_code(&self) -> Option<ConnectReturnCode> {
+        ConnectReturnCode::from_u8(self.connect_return_code)
+    }
+}
+
+///Represents the return code of a connect acknowledge packet.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConnectReturnCode {
+    ///Connection accepted.
+    #[default]
+    Accepted,
+    ///The server does not support the requested protocol level.
+    UnacceptableProtocolVersion,
+    ///The client id is not allowed by the server.
+    IdentifierRejected,
+    ///The MQTT service is unavailable.
+    ServerUnavailable,
+    ///The username or password is malformed or wrong.
+    BadUsernameOrPassword,
+    ///The client is not authorized to connect.
+    NotAuthorized,
+}
+
+impl ConnectReturnCode {
+    ///Creates a new ConnectReturnCode instance from its numeric value, or None if the value is reserved.
+    pub fn from_u8(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(ConnectReturnCode::Accepted),
+            1 => Some(ConnectReturnCode::UnacceptableProtocolVersion),
+            2 => Some(ConnectReturnCode::IdentifierRejected),
+            3 => Some(ConnectReturnCode::ServerUnavailable),
+            4 => Some(ConnectReturnCode::BadUsernameOrPassword),
+            5 => Some(ConnectReturnCode::NotAuthorized),
+            _ => None,
+        }
+    }
+    ///Converts the ConnectReturnCode instance to its numeric value.
+    pub fn to_u8(&self) -> u8 {
+        *self as u8
+    }
+}
+
 ///Represents the publish packet variable header.
+///The packet id is only present for QoS 1 and QoS 2 publications.
 #[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Publish {
     pub topic_name: EncodedString,
-    pub packet_id: Integer,
+    pub packet_id: Option<Integer>,
 }
 
 ///Represents the publish acknowledge packet variable header.
@@ -0,0 +1,102 @@
+use super::{
+    header::{FixedHeader, VariableHeader},
+    payload::Payloads,
+    ControlPacket, QOS,
+};
+
+///Represents an application message received from the server.
+#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Message {
+    topic: String,
+    payload: Vec<u8>,
+    qos: QOS,
+    retain: bool,
+    dup: bool,
+    packet_id: Option<u16>,
+}
+
+impl Message {
+    ///Creates a new Message instance from a PUBLISH control packet.
+    ///Returns None if the packet is not a PUBLISH packet.
+    pub fn from_packet(packet: ControlPacket) -> Option<Self> {
+        let FixedHeader::Publish(dup, qos, retain) = packet.header.fixed else {
+            return None;
+        };
+        let Some(VariableHeader::Publish(publish)) = packet.header.variable else {
+            return None;
+        };
+        let payload = match packet.payload.content {
+            Some(Payloads::Publish(payload)) => payload,
+            _ => Vec::new(),
+        };
+        Some(Self {
+            topic: publish.topic_name.value,
+            payload,
+            qos,
+            retain,
+            dup,
+            packet_id: publish.packet_id.map(|id| id.to_u16()),
+        })
+    }
+    ///Returns the topic the message was published to.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+    ///Returns the payload bytes of the message.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+    ///Returns the payload of the message as a string slice, or None if it is not valid utf-8.
+    pub fn payload_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.payload).ok()
+    }
+    ///Returns the QoS level the message was delivered with.
+    pub fn qos(&self) -> QOS {
+        self.qos
+    }
+    ///Returns true if the message was retained by the server.
+    pub fn retain(&self) -> bool {
+        self.retain
+    }
+    ///Returns true if the message is a redelivery of an earlier attempt.
+    pub fn dup(&self) -> bool {
+        self.dup
+    }
+    ///Returns the packet id of the message, present only for QoS 1 and QoS 2.
+    pub fn packet_id(&self) -> Option<u16> {
+        self.packet_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    #[test]
+    fn message_test() {
+        let mut bytes = VecDeque::from(vec![
+            0x3B, 0x0A, 0x00, 0x03, b'a', b'/', b'b', 0x00, 0x2A, b'h', b'i', b'!',
+        ]);
+        let packet = ControlPacket::from_bytes(&mut bytes).unwrap().unwrap();
+        let msg = Message::from_packet(packet).unwrap();
+        assert_eq!(msg.topic(), "a/b");
+        assert_eq!(msg.payload(), b"hi!");
+        assert_eq!(msg.payload_str(), Some("hi!"));
+        assert_eq!(msg.qos(), QOS::One);
+        assert!(msg.retain());
+        assert!(msg.dup());
+        assert_eq!(msg.packet_id(), Some(42));
+    }
+    #[test]
+    fn message_test1() {
+        let mut bytes = VecDeque::from(vec![0x30, 0x05, 0x00, 0x01, b't', 0xFF, 0xFE]);
+        let packet = ControlPacket::from_bytes(&mut bytes).unwrap().unwrap();
+        let msg = Message::from_packet(packet).unwrap();
+        assert_eq!(msg.payload(), &[0xFF, 0xFE]);
+        assert_eq!(msg.payload_str(), None);
+        assert_eq!(msg.packet_id(), None);
+        assert!(Message::from_packet(ControlPacket::default()).is_none());
+    }
+}
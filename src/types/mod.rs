@@ -1,10 +1,11 @@
 use std::collections::VecDeque;
 
 use header::Header;
-use payload::Payload;
+use payload::{ConnectPayload, Payload, SubscribePayload};
 
 pub mod error;
 pub mod header;
+pub mod message;
 pub mod payload;
 
 pub type CallbackFunc<'a, T, V> = Option<Box<dyn Fn(&mut T, V) + 'a>>;
@@ -36,6 +37,66 @@ impl Integer {
     }
 }
 
+///Represents a variable byte integer, used by MQTT to encode the remaining length of a packet.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VariableByteInteger {
+    value: u32,
+}
+
+impl VariableByteInteger {
+    ///The largest value that can be encoded in four bytes.
+    pub const MAX: u32 = 268_435_455;
+    ///Creates a new VariableByteInteger instance, failing if the value exceeds the maximum.
+    pub fn new(val: usize) -> crate::types::error::Result<Self> {
+        if val > Self::MAX as usize {
+            return Err(crate::types::error::Error::RemainingLengthExceededError);
+        }
+        Ok(Self { value: val as u32 })
+    }
+    ///Converts the VariableByteInteger instance to a byte vector of one to four bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        let mut val = self.value;
+        loop {
+            let mut byte = (val % 128) as u8;
+            val /= 128;
+            if val > 0 {
+                byte |= 128;
+            }
+            res.push(byte);
+            if val == 0 {
+                break;
+            }
+        }
+        res
+    }
+    ///Decodes a VariableByteInteger from the start of the given bytes.
+    ///
+    ///Returns the decoded value together with the number of bytes it occupies,
+    ///or None if the bytes end before the encoding is complete.
+    pub fn from_bytes<'b, I: IntoIterator<Item = &'b u8>>(
+        bytes: I,
+    ) -> crate::types::error::Result<Option<(Self, usize)>> {
+        let mut value = 0_u32;
+        let mut multiplier = 1_u32;
+        for (i, byte) in bytes.into_iter().enumerate() {
+            if i == 4 {
+                return Err(crate::types::error::Error::RemainingLengthExceededError);
+            }
+            value += (*byte & 127) as u32 * multiplier;
+            if *byte & 128 == 0 {
+                return Ok(Some((Self { value }, i + 1)));
+            }
+            multiplier *= 128;
+        }
+        Ok(None)
+    }
+    ///Converts the VariableByteInteger instance to a u32 value.
+    pub fn to_u32(&self) -> u32 {
+        self.value
+    }
+}
+
 ///Represents a string encoded in utf-8 format expected by MQTT.
 #[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct EncodedString {
@@ -72,6 +133,19 @@ pub enum QOS {
     Two,
 }
 
+///The kinds of packets acknowledging a QoS 1 or 2 publication.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Acknowledgement {
+    ///Acknowledges a QoS 1 publication.
+    Puback,
+    ///First acknowledgement of a QoS 2 publication.
+    Pubrec,
+    ///Answers a PUBREC for a QoS 2 publication.
+    Pubrel,
+    ///Completes a QoS 2 publication.
+    Pubcomp,
+}
+
 ///Represents an MQTT control packet.
 #[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ControlPacket {
@@ -80,163 +154,306 @@ pub struct ControlPacket {
 }
 
 impl ControlPacket {
-    ///Creates a new ControlPacket instance from a byte vector.
-    pub fn from_bytes(bytes: &mut VecDeque<u8>) -> Option<Self> {
-        let mut buf = Vec::new();
-        let packet_type = bytes.pop_front()?;
-        let len = bytes
-            .pop_front()
-            .expect("unexpected response: expected remaining length of response got None");
-        for _ in 0..len as usize {
-            buf.push(
-                bytes
-                    .pop_front()
-                    .expect("unexpected response: expected bytes of response got None"),
-            );
+    ///Creates a new ControlPacket instance from a header and a payload.
+    pub fn new(header: Header, payload: Payload) -> Self {
+        Self { header, payload }
+    }
+    ///Creates a PUBACK, PUBREC, PUBREL or PUBCOMP packet for the given packet id.
+    pub fn acknowledgement(kind: Acknowledgement, packet_id: Integer) -> Self {
+        let (fixed, variable) = match kind {
+            Acknowledgement::Puback => (
+                header::FixedHeader::Puback,
+                header::VariableHeader::Puback(header::PublishAcknowledge { packet_id }),
+            ),
+            Acknowledgement::Pubrec => (
+                header::FixedHeader::Pubrec,
+                header::VariableHeader::Pubrec(header::PublishRecieved { packet_id }),
+            ),
+            Acknowledgement::Pubrel => (
+                header::FixedHeader::Pubrel,
+                header::VariableHeader::Pubrel(header::PublishRelease { packet_id }),
+            ),
+            Acknowledgement::Pubcomp => (
+                header::FixedHeader::Pubcomp,
+                header::VariableHeader::Pubcomp(header::PublishComplete { packet_id }),
+            ),
+        };
+        ControlPacket {
+            header: Header::new(fixed, Some(variable)),
+            payload: Payload { content: None },
         }
-        match packet_type {
-            32_u8 => {
-                let header = Header::new(
-                    header::FixedHeader::Connack,
-                    Some(header::VariableHeader::Conack(header::ConnectAcknowledge {
-                        connect_acknowledge_flags: buf[0],
-                        connect_return_code: buf[1],
-                    })),
-                );
-                return Some(ControlPacket {
-                    header,
-                    payload: Payload { content: None },
+    }
+    ///Returns the header of the packet.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+    ///Returns the payload of the packet.
+    pub fn payload(&self) -> &Payload {
+        &self.payload
+    }
+    ///Decodes a ControlPacket instance from the front of a byte buffer.
+    ///
+    ///Returns `Ok(None)` and leaves the buffer untouched if it does not yet hold a complete packet.
+    ///Once a complete packet is available its bytes are removed from the buffer,
+    ///even if the packet turns out to be malformed and an error is returned.
+    pub fn from_bytes(bytes: &mut VecDeque<u8>) -> crate::types::error::Result<Option<Self>> {
+        let Some(&packet_type) = bytes.front() else {
+            return Ok(None);
+        };
+        let fixed = header::FixedHeader::from_byte(packet_type)?;
+        let Some((len, len_bytes)) = VariableByteInteger::from_bytes(bytes.iter().skip(1))? else {
+            return Ok(None);
+        };
+        let frame_len = 1 + len_bytes + len.to_u32() as usize;
+        if bytes.len() < frame_len {
+            return Ok(None);
+        }
+        let buf: Vec<u8> = bytes.drain(..frame_len).skip(1 + len_bytes).collect();
+        Self::decode(fixed, &buf).map(Some)
+    }
+    ///Decodes the variable header and payload of a packet with the given fixed header.
+    fn decode(fixed: header::FixedHeader, buf: &[u8]) -> crate::types::error::Result<Self> {
+        let mut reader = Reader::new(buf);
+        let (variable, content) = match fixed {
+            header::FixedHeader::Connect => {
+                let connect = header::Connect {
+                    protocol_name: reader.read_string()?,
+                    protocol_level: reader.read_u8()?,
+                    connect_flags: reader.read_u8()?,
+                    keep_alive: reader.read_integer()?,
+                };
+                let flags = connect.connect_flags;
+                if flags & 1 != 0 {
+                    return Err(crate::types::error::Error::MalformedPacketError);
+                }
+                let client_id = reader.read_string()?;
+                let (will_topic, will_message) = if flags & 2_u8.pow(2) != 0 {
+                    (Some(reader.read_string()?), Some(reader.read_string()?))
+                } else {
+                    (None, None)
+                };
+                let username = if flags & 2_u8.pow(7) != 0 {
+                    Some(reader.read_string()?)
+                } else {
+                    None
+                };
+                let password = if flags & 2_u8.pow(6) != 0 {
+                    Some(reader.read_string()?)
+                } else {
+                    None
+                };
+                let content = payload::Payloads::Connect(ConnectPayload::new(
+                    &client_id.value,
+                    will_topic.as_ref().map(|v| v.value.as_str()),
+                    will_message.as_ref().map(|v| v.value.as_str()),
+                    username.map(|v| v.value),
+                    password.map(|v| v.value),
+                ));
+                (Some(header::VariableHeader::Connect(connect)), Some(content))
+            }
+            header::FixedHeader::Connack => {
+                let variable = header::VariableHeader::Conack(header::ConnectAcknowledge {
+                    connect_acknowledge_flags: reader.read_u8()?,
+                    connect_return_code: reader.read_u8()?,
                 });
+                (Some(variable), None)
             }
-            64_u8 => {
-                let header = Header::new(
-                    header::FixedHeader::Puback,
-                    Some(header::VariableHeader::Puback(header::PublishAcknowledge {
-                        packet_id: Integer {
-                            msb: buf[0],
-                            lsb: buf[1],
-                        },
-                    })),
-                );
-                return Some(ControlPacket {
-                    header,
-                    payload: Payload { content: None },
+            header::FixedHeader::Publish(_, qos, _) => {
+                let topic_name = reader.read_string()?;
+                let packet_id = if qos != QOS::Zero {
+                    Some(reader.read_packet_id()?)
+                } else {
+                    None
+                };
+                let variable = header::VariableHeader::Publish(header::Publish {
+                    topic_name,
+                    packet_id,
                 });
+                let content = payload::Payloads::Publish(reader.read_rest().to_vec());
+                (Some(variable), Some(content))
             }
-            80_u8 => {
-                let header = Header::new(
-                    header::FixedHeader::Pubrec,
-                    Some(header::VariableHeader::Pubrec(header::PublishRecieved {
-                        packet_id: Integer {
-                            msb: buf[0],
-                            lsb: buf[1],
-                        },
-                    })),
-                );
-                return Some(ControlPacket {
-                    header,
-                    payload: Payload { content: None },
+            header::FixedHeader::Puback => {
+                let variable = header::VariableHeader::Puback(header::PublishAcknowledge {
+                    packet_id: reader.read_integer()?,
                 });
+                (Some(variable), None)
             }
-            98_u8 => {
-                let header = Header::new(
-                    header::FixedHeader::Pubrel,
-                    Some(header::VariableHeader::Pubrel(header::PublishRelease {
-                        packet_id: Integer {
-                            msb: buf[0],
-                            lsb: buf[1],
-                        },
-                    })),
-                );
-                return Some(ControlPacket {
-                    header,
-                    payload: Payload { content: None },
+            header::FixedHeader::Pubrec => {
+                let variable = header::VariableHeader::Pubrec(header::PublishRecieved {
+                    packet_id: reader.read_integer()?,
                 });
+                (Some(variable), None)
             }
-            112_u8 => {
-                let header = Header::new(
-                    header::FixedHeader::Pubcomp,
-                    Some(header::VariableHeader::Pubcomp(header::PublishComplete {
-                        packet_id: Integer {
-                            msb: buf[0],
-                            lsb: buf[1],
-                        },
-                    })),
-                );
-                return Some(ControlPacket {
-                    header,
-                    payload: Payload { content: None },
+            header::FixedHeader::Pubrel => {
+                let variable = header::VariableHeader::Pubrel(header::PublishRelease {
+                    packet_id: reader.read_integer()?,
                 });
+                (Some(variable), None)
             }
-            144_u8 => {
-                let header = Header::new(
-                    header::FixedHeader::Suback,
-                    Some(header::VariableHeader::Suback(header::Subscribe {
-                        packet_id: Integer {
-                            msb: buf[0],
-                            lsb: buf[1],
-                        },
-                    })),
-                );
-                return Some(ControlPacket {
-                    header,
-                    payload: Payload {
-                        content: Some(payload::Payloads::SubAcknowledge(
-                            buf.as_slice()[2..]
-                                .to_vec()
-                                .iter()
-                                .map(|b| match b {
-                                    0 => {
-                                        Ok(QOS::Zero)
-                                    }
-                                    1 => {
-                                        Ok(QOS::One)
-                                    }
-                                    2 => {
-                                        Ok(QOS::Two)
-                                    }
-                                    _ => {
-                                        Err(crate::types::error::Error::SubscriptionAckhowledgeFailureError)
-                                    }
-                                })
-                                .collect(),
-                        )),
-                    },
+            header::FixedHeader::Pubcomp => {
+                let variable = header::VariableHeader::Pubcomp(header::PublishComplete {
+                    packet_id: reader.read_integer()?,
                 });
+                (Some(variable), None)
             }
-            176_u8 => {
-                let header = Header::new(
-                    header::FixedHeader::Unsuback,
-                    Some(header::VariableHeader::Unsuback(header::Unsubscribe {
-                        packet_id: Integer {
-                            msb: buf[0],
-                            lsb: buf[1],
-                        },
-                    })),
-                );
-                return Some(ControlPacket {
-                    header,
-                    payload: Payload { content: None },
+            header::FixedHeader::Subscribe => {
+                let variable = header::VariableHeader::Subscribe(header::Subscribe {
+                    packet_id: reader.read_integer()?,
                 });
+                let mut topics = Vec::new();
+                while !reader.is_empty() {
+                    let topic_filter = reader.read_string()?;
+                    let qos = match reader.read_u8()? {
+                        0 => QOS::Zero,
+                        1 => QOS::One,
+                        2 => QOS::Two,
+                        _ => return Err(crate::types::error::Error::MalformedPacketError),
+                    };
+                    topics.push(SubscribePayload::new(&topic_filter.value, qos));
+                }
+                if topics.is_empty() {
+                    return Err(crate::types::error::Error::MalformedPacketError);
+                }
+                (Some(variable), Some(payload::Payloads::Subscribe(topics)))
             }
-            208_u8 => {
-                let header = Header::new(header::FixedHeader::Pingresp, None);
-                return Some(ControlPacket {
-                    header,
-                    payload: Payload { content: None },
+            header::FixedHeader::Suback => {
+                let variable = header::VariableHeader::Suback(header::Subscribe {
+                    packet_id: reader.read_integer()?,
                 });
+                let codes = reader
+                    .read_rest()
+                    .iter()
+                    .map(|b| match b {
+                        0 => Ok(Ok(QOS::Zero)),
+                        1 => Ok(Ok(QOS::One)),
+                        2 => Ok(Ok(QOS::Two)),
+                        128 => Ok(Err(
+                            crate::types::error::Error::SubscriptionAckhowledgeFailureError,
+                        )),
+                        _ => Err(crate::types::error::Error::MalformedPacketError),
+                    })
+                    .collect::<crate::types::error::Result<_>>()?;
+                (
+                    Some(variable),
+                    Some(payload::Payloads::SubAcknowledge(codes)),
+                )
             }
-            _ => {}
-        }
-        None
+            header::FixedHeader::Unsuback => {
+                let variable = header::VariableHeader::Unsuback(header::Unsubscribe {
+                    packet_id: reader.read_integer()?,
+                });
+                (Some(variable), None)
+            }
+            header::FixedHeader::Unsubscribe => {
+                let variable = header::VariableHeader::Unsubscribe(header::Unsubscribe {
+                    packet_id: reader.read_integer()?,
+                });
+                let mut topics = Vec::new();
+                while !reader.is_empty() {
+                    topics.push(reader.read_string()?);
+                }
+                if topics.is_empty() {
+                    return Err(crate::types::error::Error::MalformedPacketError);
+                }
+                (Some(variable), Some(payload::Payloads::Unsubscribe(topics)))
+            }
+            header::FixedHeader::Pingreq
+            | header::FixedHeader::Pingresp
+            | header::FixedHeader::Disconnect => (None, None),
+        };
+        reader.finish()?;
+        Ok(ControlPacket {
+            header: Header::new(fixed, variable),
+            payload: Payload { content },
+        })
     }
     ///Converts the ControlPacket instance to a byte vector.
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut res = Vec::new();
-        res.extend(self.header.to_bytes());
-        res.extend(self.payload.to_bytes());
-        res[1] = res.len() as u8;
-        res
+    pub fn to_bytes(&self) -> crate::types::error::Result<Vec<u8>> {
+        let payload = self.payload.to_bytes();
+        let mut res = self.header.to_bytes(payload.len())?;
+        res.extend(payload);
+        Ok(res)
+    }
+}
+
+///Reads the fields of a packet body, failing instead of panicking when the body is too short.
+pub(crate) struct Reader<'b> {
+    buf: &'b [u8],
+    pos: usize,
+}
+
+impl<'b> Reader<'b> {
+    ///Creates a new Reader instance over the given bytes.
+    pub(crate) fn new(buf: &'b [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+    ///Reads the given number of bytes.
+    pub(crate) fn read_bytes(&mut self, len: usize) -> crate::types::error::Result<&'b [u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or(crate::types::error::Error::TruncatedPacketError)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+    ///Reads a single byte.
+    pub(crate) fn read_u8(&mut self) -> crate::types::error::Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+    ///Reads a 16-bit integer.
+    pub(crate) fn read_integer(&mut self) -> crate::types::error::Result<Integer> {
+        let bytes = self.read_bytes(2)?;
+        Ok(Integer {
+            msb: bytes[0],
+            lsb: bytes[1],
+        })
+    }
+    ///Reads a packet id, which must not be 0.
+    pub(crate) fn read_packet_id(&mut self) -> crate::types::error::Result<Integer> {
+        let packet_id = self.read_integer()?;
+        if packet_id.to_u16() == 0 {
+            return Err(crate::types::error::Error::MalformedPacketError);
+        }
+        Ok(packet_id)
+    }
+    ///Reads a length-prefixed utf-8 string.
+    pub(crate) fn read_string(&mut self) -> crate::types::error::Result<EncodedString> {
+        let len = self.read_integer()?.to_u16() as usize;
+        let value = std::str::from_utf8(self.read_bytes(len)?)
+            .map_err(|_| crate::types::error::Error::MalformedPacketError)?;
+        Ok(EncodedString::new(value))
+    }
+    ///Reads a complete control packet.
+    pub(crate) fn read_packet(&mut self) -> crate::types::error::Result<ControlPacket> {
+        let (len, len_bytes) = VariableByteInteger::from_bytes(self.buf.get(self.pos + 1..).unwrap_or_default())?
+            .ok_or(crate::types::error::Error::TruncatedPacketError)?;
+        let frame = self.read_bytes(1 + len_bytes + len.to_u32() as usize)?;
+        ControlPacket::from_bytes(&mut frame.iter().copied().collect())?
+            .ok_or(crate::types::error::Error::TruncatedPacketError)
+    }
+    ///Reads a variable byte integer.
+    pub(crate) fn read_variable_integer(&mut self) -> crate::types::error::Result<VariableByteInteger> {
+        let (value, len) = VariableByteInteger::from_bytes(&self.buf[self.pos..])?
+            .ok_or(crate::types::error::Error::TruncatedPacketError)?;
+        self.pos += len;
+        Ok(value)
+    }
+    ///Reads all of the remaining bytes.
+    pub(crate) fn read_rest(&mut self) -> &'b [u8] {
+        let bytes = &self.buf[self.pos..];
+        self.pos = self.buf.len();
+        bytes
+    }
+    ///Returns true if all bytes have been read.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pos == self.buf.len()
+    }
+    ///Fails if any bytes were left unread.
+    pub(crate) fn finish(&self) -> crate::types::error::Result<()> {
+        if self.pos != self.buf.len() {
+            return Err(crate::types::error::Error::MalformedPacketError);
+        }
+        Ok(())
     }
 }
 
@@ -256,3 +473,354 @@ pub struct Will {
     pub qos: QOS,
     pub retain: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remaining_length_test() {
+        let cases: [(usize, &[u8]); 8] = [
+            (0, &[0x00]),
+            (127, &[0x7F]),
+            (128, &[0x80, 0x01]),
+            (16_383, &[0xFF, 0x7F]),
+            (16_384, &[0x80, 0x80, 0x01]),
+            (2_097_151, &[0xFF, 0xFF, 0x7F]),
+            (2_097_152, &[0x80, 0x80, 0x80, 0x01]),
+            (268_435_455, &[0xFF, 0xFF, 0xFF, 0x7F]),
+        ];
+        for (val, bytes) in cases {
+            let vbi = VariableByteInteger::new(val).unwrap();
+            assert_eq!(vbi.to_bytes(), bytes);
+            assert_eq!(
+                VariableByteInteger::from_bytes(bytes).unwrap(),
+                Some((vbi, bytes.len()))
+            );
+        }
+    }
+    #[test]
+    fn remaining_length_test1() {
+        assert_eq!(
+            VariableByteInteger::new(268_435_456),
+            Err(error::Error::RemainingLengthExceededError)
+        );
+        assert_eq!(
+            VariableByteInteger::from_bytes(&[0xFF, 0xFF, 0xFF, 0xFF, 0x01]),
+            Err(error::Error::RemainingLengthExceededError)
+        );
+        assert_eq!(VariableByteInteger::from_bytes(&[0xFF, 0xFF]), Ok(None));
+    }
+    #[test]
+    fn remaining_length_test2() {
+        let packet = ControlPacket {
+            header: Header::new(
+                header::FixedHeader::Publish(false, QOS::Zero, false),
+                Some(header::VariableHeader::Publish(header::Publish {
+                    topic_name: EncodedString::new("a/b"),
+                    packet_id: None,
+                })),
+            ),
+            payload: Payload {
+                content: Some(payload::Payloads::Publish(vec![b'x'; 4096])),
+            },
+        };
+        let bytes = packet.to_bytes().unwrap();
+        let remaining = 5 + 4096;
+        assert_eq!(bytes.len(), 1 + 2 + remaining);
+        assert_eq!(
+            VariableByteInteger::from_bytes(&bytes[1..]).unwrap(),
+            Some((VariableByteInteger::new(remaining).unwrap(), 2))
+        );
+    }
+    #[test]
+    fn publish_decode_test() {
+        let mut bytes = VecDeque::from(vec![
+            0x3B, 0x0A, 0x00, 0x03, b'a', b'/', b'b', 0x00, 0x2A, b'h', b'i', b'!',
+        ]);
+        let packet = ControlPacket::from_bytes(&mut bytes).unwrap().unwrap();
+        assert_eq!(
+            packet.header.fixed,
+            header::FixedHeader::Publish(true, QOS::One, true)
+        );
+        assert_eq!(
+            packet.header.variable,
+            Some(header::VariableHeader::Publish(header::Publish {
+                topic_name: EncodedString::new("a/b"),
+                packet_id: Some(Integer::new(42)),
+            }))
+        );
+        assert_eq!(
+            packet.payload.content,
+            Some(payload::Payloads::Publish(b"hi!".to_vec()))
+        );
+        assert!(bytes.is_empty());
+    }
+    #[test]
+    fn publish_decode_test1() {
+        let mut bytes = VecDeque::from(vec![0x30, 0x06, 0x00, 0x01, b't', b'm', b's', b'g']);
+        let packet = ControlPacket::from_bytes(&mut bytes).unwrap().unwrap();
+        assert_eq!(
+            packet.header.fixed,
+            header::FixedHeader::Publish(false, QOS::Zero, false)
+        );
+        assert_eq!(
+            packet.header.variable,
+            Some(header::VariableHeader::Publish(header::Publish {
+                topic_name: EncodedString::new("t"),
+                packet_id: None,
+            }))
+        );
+        assert_eq!(
+            packet.payload.content,
+            Some(payload::Payloads::Publish(b"msg".to_vec()))
+        );
+    }
+    #[test]
+    fn publish_decode_test2() {
+        let mut bytes = VecDeque::from(vec![0x36, 0x03, 0x00, 0x01, b't']);
+        assert_eq!(
+            ControlPacket::from_bytes(&mut bytes),
+            Err(error::Error::InvalidReservedFlagsError(0x36))
+        );
+    }
+    #[test]
+    fn partial_decode_test() {
+        let frame = [0x90, 0x03, 0x00, 0x07, 0x01];
+        let mut bytes = VecDeque::new();
+        for (i, byte) in frame.iter().enumerate() {
+            assert_eq!(ControlPacket::from_bytes(&mut bytes), Ok(None));
+            assert_eq!(bytes.len(), i);
+            bytes.push_back(*byte);
+        }
+        bytes.extend([0xD0, 0x00]);
+        let suback = ControlPacket::from_bytes(&mut bytes).unwrap().unwrap();
+        assert_eq!(suback.header.fixed, header::FixedHeader::Suback);
+        assert_eq!(
+            suback.payload.content,
+            Some(payload::Payloads::SubAcknowledge(vec![Ok(QOS::One)]))
+        );
+        let pingresp = ControlPacket::from_bytes(&mut bytes).unwrap().unwrap();
+        assert_eq!(pingresp.header.fixed, header::FixedHeader::Pingresp);
+        assert!(bytes.is_empty());
+    }
+    #[test]
+    fn malformed_decode_test() {
+        let mut bytes = VecDeque::from(vec![0x41, 0x02, 0x00, 0x01]);
+        assert_eq!(
+            ControlPacket::from_bytes(&mut bytes),
+            Err(error::Error::InvalidReservedFlagsError(0x41))
+        );
+        let mut bytes = VecDeque::from(vec![0xF0, 0x00]);
+        assert_eq!(
+            ControlPacket::from_bytes(&mut bytes),
+            Err(error::Error::UnknownPacketTypeError(0xF0))
+        );
+        let mut bytes = VecDeque::from(vec![0x40, 0x01, 0x00]);
+        assert_eq!(
+            ControlPacket::from_bytes(&mut bytes),
+            Err(error::Error::TruncatedPacketError)
+        );
+        assert!(bytes.is_empty());
+        let mut bytes = VecDeque::from(vec![0x30, 0x03, 0x00, 0x05, b't']);
+        assert_eq!(
+            ControlPacket::from_bytes(&mut bytes),
+            Err(error::Error::TruncatedPacketError)
+        );
+        let mut bytes = VecDeque::from(vec![0x20, 0x03, 0x00, 0x00, 0x00]);
+        assert_eq!(
+            ControlPacket::from_bytes(&mut bytes),
+            Err(error::Error::MalformedPacketError)
+        );
+        let mut bytes = VecDeque::from(vec![0x32, 0x05, 0x00, 0x01, b't', 0x00, 0x00]);
+        assert_eq!(
+            ControlPacket::from_bytes(&mut bytes),
+            Err(error::Error::MalformedPacketError)
+        );
+        let mut bytes = VecDeque::from(vec![0x82, 0x02, 0x00, 0x01]);
+        assert_eq!(
+            ControlPacket::from_bytes(&mut bytes),
+            Err(error::Error::MalformedPacketError)
+        );
+        let mut bytes = VecDeque::from(vec![0xA2, 0x02, 0x00, 0x01]);
+        assert_eq!(
+            ControlPacket::from_bytes(&mut bytes),
+            Err(error::Error::MalformedPacketError)
+        );
+    }
+    #[test]
+    fn round_trip_test() {
+        let packet_id = Integer::new(513);
+        let packets = vec![
+            ControlPacket::new(
+                Header::new(
+                    header::FixedHeader::Connect,
+                    Some(header::VariableHeader::Connect(header::Connect {
+                        protocol_name: EncodedString::new("MQTT"),
+                        protocol_level: 4,
+                        connect_flags: 2_u8.pow(7) + 2_u8.pow(6) + 2_u8.pow(2) + 2,
+                        keep_alive: Integer::new(60),
+                    })),
+                ),
+                Payload {
+                    content: Some(payload::Payloads::Connect(ConnectPayload::new(
+                        "client",
+                        Some("will/topic"),
+                        Some("gone"),
+                        Some("user".to_owned()),
+                        Some("pass".to_owned()),
+                    ))),
+                },
+            ),
+            ControlPacket::new(
+                Header::new(
+                    header::FixedHeader::Connect,
+                    Some(header::VariableHeader::Connect(header::Connect {
+                        protocol_name: EncodedString::new("MQTT"),
+                        protocol_level: 4,
+                        connect_flags: 0,
+                        keep_alive: Integer::new(0),
+                    })),
+                ),
+                Payload {
+                    content: Some(payload::Payloads::Connect(ConnectPayload::new(
+                        "", None, None, None, None,
+                    ))),
+                },
+            ),
+            ControlPacket::new(
+                Header::new(
+                    header::FixedHeader::Connack,
+                    Some(header::VariableHeader::Conack(header::ConnectAcknowledge {
+                        connect_acknowledge_flags: 1,
+                        connect_return_code: 5,
+                    })),
+                ),
+                Payload::default(),
+            ),
+            ControlPacket::new(
+                Header::new(
+                    header::FixedHeader::Publish(true, QOS::Two, true),
+                    Some(header::VariableHeader::Publish(header::Publish {
+                        topic_name: EncodedString::new("a/b"),
+                        packet_id: Some(packet_id),
+                    })),
+                ),
+                Payload {
+                    content: Some(payload::Payloads::Publish(vec![0, 1, 2, 255])),
+                },
+            ),
+            ControlPacket::new(
+                Header::new(
+                    header::FixedHeader::Puback,
+                    Some(header::VariableHeader::Puback(header::PublishAcknowledge {
+                        packet_id,
+                    })),
+                ),
+                Payload::default(),
+            ),
+            ControlPacket::new(
+                Header::new(
+                    header::FixedHeader::Pubrec,
+                    Some(header::VariableHeader::Pubrec(header::PublishRecieved {
+                        packet_id,
+                    })),
+                ),
+                Payload::default(),
+            ),
+            ControlPacket::new(
+                Header::new(
+                    header::FixedHeader::Pubrel,
+                    Some(header::VariableHeader::Pubrel(header::PublishRelease {
+                        packet_id,
+                    })),
+                ),
+                Payload::default(),
+            ),
+            ControlPacket::new(
+                Header::new(
+                    header::FixedHeader::Pubcomp,
+                    Some(header::VariableHeader::Pubcomp(header::PublishComplete {
+                        packet_id,
+                    })),
+                ),
+                Payload::default(),
+            ),
+            ControlPacket::new(
+                Header::new(
+                    header::FixedHeader::Subscribe,
+                    Some(header::VariableHeader::Subscribe(header::Subscribe { packet_id })),
+                ),
+                Payload {
+                    content: Some(payload::Payloads::Subscribe(vec![
+                        SubscribePayload::new("a/+", QOS::One),
+                        SubscribePayload::new("b/#", QOS::Two),
+                    ])),
+                },
+            ),
+            ControlPacket::new(
+                Header::new(
+                    header::FixedHeader::Suback,
+                    Some(header::VariableHeader::Suback(header::Subscribe { packet_id })),
+                ),
+                Payload {
+                    content: Some(payload::Payloads::SubAcknowledge(vec![
+                        Ok(QOS::Zero),
+                        Err(error::Error::SubscriptionAckhowledgeFailureError),
+                    ])),
+                },
+            ),
+            ControlPacket::new(
+                Header::new(
+                    header::FixedHeader::Unsubscribe,
+                    Some(header::VariableHeader::Unsubscribe(header::Unsubscribe {
+                        packet_id,
+                    })),
+                ),
+                Payload {
+                    content: Some(payload::Payloads::Unsubscribe(vec![
+                        EncodedString::new("a/+"),
+                        EncodedString::new("b/#"),
+                    ])),
+                },
+            ),
+            ControlPacket::new(
+                Header::new(
+                    header::FixedHeader::Unsuback,
+                    Some(header::VariableHeader::Unsuback(header::Unsubscribe {
+                        packet_id,
+                    })),
+                ),
+                Payload::default(),
+            ),
+            ControlPacket::new(Header::new(header::FixedHeader::Pingreq, None), Payload::default()),
+            ControlPacket::new(Header::new(header::FixedHeader::Pingresp, None), Payload::default()),
+            ControlPacket::new(
+                Header::new(header::FixedHeader::Disconnect, None),
+                Payload::default(),
+            ),
+        ];
+        for packet in packets {
+            let mut bytes = VecDeque::from(packet.to_bytes().unwrap());
+            assert_eq!(ControlPacket::from_bytes(&mut bytes), Ok(Some(packet)));
+            assert!(bytes.is_empty());
+        }
+    }
+    #[test]
+    fn round_trip_test1() {
+        let expected: [(header::FixedHeader, u8); 4] = [
+            (header::FixedHeader::Pubrel, 0x62),
+            (header::FixedHeader::Subscribe, 0x82),
+            (header::FixedHeader::Pingreq, 0xC0),
+            (header::FixedHeader::Disconnect, 0xE0),
+        ];
+        for (fixed, byte) in expected {
+            assert_eq!(fixed.to_bytes(0).unwrap(), vec![byte, 0]);
+            assert_eq!(header::FixedHeader::from_byte(byte), Ok(fixed));
+        }
+        assert_eq!(
+            header::FixedHeader::from_byte(0x80),
+            Err(error::Error::InvalidReservedFlagsError(0x80))
+        );
+    }
+}
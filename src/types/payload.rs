@@ -43,6 +43,16 @@ impl Payloads {
                 }
                 res
             },
+            Payloads::SubAcknowledge(p) => {
+                p.iter()
+                    .map(|code| match code {
+                        Ok(QOS::Zero) => 0_u8,
+                        Ok(QOS::One) => 1_u8,
+                        Ok(QOS::Two) => 2_u8,
+                        Err(_) => 128_u8,
+                    })
+                    .collect()
+            },
             Payloads::Unsubscribe(p) => {
                 let mut res = Vec::new();
                 for s in p {
@@ -50,7 +60,7 @@ impl Payloads {
                 }
                 res
             },
-            _ => {vec![]},
+            Payloads::Default => {vec![]},
         }
     }
 }
@@ -86,6 +96,26 @@ impl ConnectPayload {
             password,
         }
     }
+    ///Returns the client id.
+    pub fn client_id(&self) -> &str {
+        &self.client_id.value
+    }
+    ///Returns the will topic, if a will is set.
+    pub fn will_topic(&self) -> Option<&str> {
+        self.will_topic.as_ref().map(|v| v.value.as_str())
+    }
+    ///Returns the will message, if a will is set.
+    pub fn will_message(&self) -> Option<&str> {
+        self.will_message.as_ref().map(|v| v.value.as_str())
+    }
+    ///Returns the username, if one is set.
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_ref().map(|v| v.value.as_str())
+    }
+    ///Returns the password, if one is set.
+    pub fn password(&self) -> Option<&str> {
+        self.password.as_ref().map(|v| v.value.as_str())
+    }
     ///Converts the ConnectPayload instance to a byte vector.
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut res = Vec::new();
@@ -122,6 +152,14 @@ impl SubscribePayload {
             qos,
         }
     }
+    ///Returns the topic filter.
+    pub fn topic_filter(&self) -> &str {
+        &self.topic_filter.value
+    }
+    ///Returns the requested QoS.
+    pub fn qos(&self) -> QOS {
+        self.qos
+    }
     ///Converts the SubscribePayload instance to a byte vector.
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut res = Vec::new();
@@ -0,0 +1,537 @@
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind},
+    sync::Mutex,
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    random::random_bytes,
+    transport::{self, Transport},
+};
+
+///The GUID appended to the handshake key before hashing it into `Sec-WebSocket-Accept`.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+///The longest handshake response accepted from the server.
+const MAX_RESPONSE_LENGTH: usize = 8192;
+///The largest frame payload accepted from the server by default.
+const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+///The largest payload of a control frame.
+const MAX_CONTROL_PAYLOAD: u8 = 125;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+///Represents the WebSocket settings of a `ws://` or `wss://` connection: extra headers sent with the opening handshake
+///and the largest frame accepted from the server.
+///The request path is taken from the connection URI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebSocketOptions {
+    headers: Vec<(String, String)>,
+    max_frame_size: usize,
+}
+
+impl Default for WebSocketOptions {
+    fn default() -> Self {
+        Self {
+            headers: Vec::new(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+}
+
+impl WebSocketOptions {
+    ///Creates a new WebSocketOptions instance without extra headers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    ///Adds a header to the opening handshake, such as `Authorization` or `Origin`.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+    ///Sets the largest frame payload accepted from the server, 1 MiB by default. Larger frames fail the read.
+    pub fn max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+}
+
+///Represents the reading side of a WebSocket: bytes read but not yet parsed into frames,
+///and message payload not yet handed out.
+#[derive(Debug, Default)]
+struct FrameReader {
+    bytes: VecDeque<u8>,
+    payload: VecDeque<u8>,
+    max_frame_size: usize,
+}
+
+///Represents a WebSocket connection speaking the `mqtt` subprotocol over another transport.
+///
+///Every write is sent as one masked binary frame, while reads hand out the payload of binary frames as a byte stream,
+///so packets split across frames or frames holding several packets are decoded as usual.
+#[derive(Debug)]
+pub struct WebSocketTransport {
+    inner: Box<dyn Transport>,
+    reader: Mutex<FrameReader>,
+    writer: Mutex<()>,
+}
+
+impl WebSocketTransport {
+    ///Creates a new WebSocketTransport instance over a connected transport, completing the opening handshake within the timeout.
+    ///The path is percent-encoded in the request line.
+    ///
+    ///Fails with `Error::WebSocketHandshakeError` if the path or an extra header holds a control character,
+    ///or if the server does not upgrade the connection to the `mqtt` subprotocol.
+    pub fn new(
+        inner: Box<dyn Transport>,
+        host: &str,
+        path: &str,
+        options: &WebSocketOptions,
+        timeout: Duration,
+    ) -> crate::types::error::Result<Self> {
+        if path.chars().any(char::is_control) {
+            return Err(handshake_error("control character in path".to_owned()));
+        }
+        for (name, value) in &options.headers {
+            if name.is_empty() || !name.bytes().all(|b| b.is_ascii_graphic() && b != b':') {
+                return Err(handshake_error(format!("invalid header name `{}`", name.escape_debug())));
+            }
+            if value.chars().any(|c| c.is_control() && c != '\t') {
+                return Err(handshake_error(format!("control character in header `{name}`")));
+            }
+        }
+        let path = encode_path(path);
+        let key = STANDARD.encode(random_bytes::<16>());
+        let mut request = format!(
+            "GET {path} HTTP/1.1\r\nHost: {host}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: mqtt\r\n"
+        );
+        for (name, value) in &options.headers {
+            request.push_str(&format!("{name}: {value}\r\n"));
+        }
+        request.push_str("\r\n");
+        inner
+            .write_all(request.as_bytes())
+            .map_err(|_| crate::types::error::Error::RequestError)?;
+        inner
+            .set_read_timeout(Some(timeout))
+            .map_err(|_| crate::types::error::Error::ConnectionError)?;
+        let res = read_response(inner.as_ref());
+        let _ = inner.set_read_timeout(None);
+        let (response, rest) = res?;
+        check_response(&response, &key)?;
+        Ok(Self {
+            inner,
+            reader: Mutex::new(FrameReader {
+                bytes: rest.into(),
+                payload: VecDeque::new(),
+                max_frame_size: options.max_frame_size,
+            }),
+            writer: Mutex::new(()),
+        })
+    }
+    ///Writes a single frame with the given opcode, masked as every client frame must be.
+    fn write_frame(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 14);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(0x80 | 126);
+                frame.extend((len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend((len as u64).to_be_bytes());
+            }
+        }
+        let mask = random_bytes::<4>();
+        frame.extend(mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        let _writer = self.writer.lock().expect("FATAL: WebSocket writer lock poisoned");
+        self.inner.write_all(&frame)
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut reader = self.reader.lock().expect("FATAL: WebSocket reader lock poisoned");
+        loop {
+            if !reader.payload.is_empty() {
+                let n = buf.len().min(reader.payload.len());
+                for (dst, src) in buf.iter_mut().zip(reader.payload.drain(..n)) {
+                    *dst = src;
+                }
+                return Ok(n);
+            }
+            let max_frame_size = reader.max_frame_size;
+            let Some((opcode, payload)) = parse_frame(&mut reader.bytes, max_frame_size)? else {
+                let mut raw = [0_u8; 4096];
+                let n = self.inner.read(&mut raw)?;
+                if n == 0 {
+                    return Ok(0);
+                }
+                reader.bytes.extend(&raw[..n]);
+                continue;
+            };
+            match opcode {
+                OPCODE_BINARY | OPCODE_CONTINUATION => reader.payload.extend(payload),
+                OPCODE_PING => self.write_frame(OPCODE_PONG, &payload)?,
+                OPCODE_PONG => {}
+                OPCODE_CLOSE => {
+                    let _ = self.write_frame(OPCODE_CLOSE, payload.get(..2).unwrap_or_default());
+                    return Ok(0);
+                }
+                OPCODE_TEXT => return Err(invalid_data("unexpected text frame")),
+                _ => return Err(invalid_data("unknown opcode")),
+            }
+        }
+    }
+    fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        self.write_frame(OPCODE_BINARY, buf)
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        let _ = self.write_frame(OPCODE_CLOSE, &1000_u16.to_be_bytes());
+        self.inner.shutdown()
+    }
+}
+
+///Reads the handshake response up to its blank line, returning it with any bytes read past it.
+fn read_response(inner: &dyn Transport) -> crate::types::error::Result<(String, Vec<u8>)> {
+    let mut bytes = Vec::new();
+    loop {
+        if let Some(end) = bytes.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = bytes.split_off(end + 4);
+            let response = String::from_utf8(bytes)
+                .map_err(|_| handshake_error("response is not UTF-8".to_owned()))?;
+            return Ok((response, rest));
+        }
+        if bytes.len() > MAX_RESPONSE_LENGTH {
+            return Err(handshake_error("response headers too long".to_owned()));
+        }
+        let mut buf = [0_u8; 1024];
+        match inner.read(&mut buf) {
+            Ok(0) => return Err(handshake_error("connection closed during handshake".to_owned())),
+            Ok(n) => bytes.extend(&buf[..n]),
+            Err(e) => return Err(transport::connection_error(e)),
+        }
+    }
+}
+
+///Checks that the handshake response upgrades the connection to the `mqtt` subprotocol and answers the key.
+fn check_response(response: &str, key: &str) -> crate::types::error::Result<()> {
+    let mut lines = response.split("\r\n");
+    let status = lines.next().unwrap_or_default();
+    if status.split(' ').nth(1) != Some("101") {
+        return Err(handshake_error(format!("unexpected status `{status}`")));
+    }
+    let header = |name: &str| {
+        response
+            .split("\r\n")
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .find(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.trim().to_owned())
+    };
+    if !header("Upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket")) {
+        return Err(handshake_error("missing `Upgrade: websocket`".to_owned()));
+    }
+    let accept = STANDARD.encode(Sha1::digest(format!("{key}{ACCEPT_GUID}")));
+    if header("Sec-WebSocket-Accept").as_deref() != Some(accept.as_str()) {
+        return Err(handshake_error("invalid `Sec-WebSocket-Accept`".to_owned()));
+    }
+    if header("Sec-WebSocket-Protocol").as_deref() != Some("mqtt") {
+        return Err(handshake_error("server did not accept the `mqtt` subprotocol".to_owned()));
+    }
+    Ok(())
+}
+
+///Parses one server frame from the front of the buffer.
+///Returns None and leaves the buffer untouched until the whole frame has been read.
+///Fails if the payload is longer than `max_frame_size`, if the frame is masked, or if a control frame is fragmented
+///or longer than 125 bytes.
+fn parse_frame(bytes: &mut VecDeque<u8>, max_frame_size: usize) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let (Some(&first), Some(&second)) = (bytes.front(), bytes.get(1)) else {
+        return Ok(None);
+    };
+    if first & 0x70 != 0 {
+        return Err(invalid_data("reserved bits set"));
+    }
+    if second & 0x80 != 0 {
+        return Err(invalid_data("masked frame from server"));
+    }
+    if first & 0x08 != 0 && (first & 0x80 == 0 || second & 0x7F > MAX_CONTROL_PAYLOAD) {
+        return Err(invalid_data("invalid control frame"));
+    }
+    let (len, header_len): (u64, usize) = match second & 0x7F {
+        126 if bytes.len() >= 4 => (u16::from_be_bytes([bytes[2], bytes[3]]) as u64, 4),
+        127 if bytes.len() >= 10 => {
+            if bytes[2] & 0x80 != 0 {
+                return Err(invalid_data("most significant bit of the frame length set"));
+            }
+            let len = bytes.range(2..10).fold(0_u64, |acc, b| acc << 8 | *b as u64);
+            (len, 10)
+        }
+        126 | 127 => return Ok(None),
+        len => (len as u64, 2),
+    };
+    let len = usize::try_from(len)
+        .ok()
+        .filter(|len| *len <= max_frame_size)
+        .ok_or_else(|| invalid_data("frame too long"))?;
+    let frame_len = header_len
+        .checked_add(len)
+        .ok_or_else(|| invalid_data("frame too long"))?;
+    if bytes.len() < frame_len {
+        return Ok(None);
+    }
+    bytes.drain(..header_len);
+    let payload = bytes.drain(..len).collect();
+    Ok(Some((first & 0x0F, payload)))
+}
+
+///Percent-encodes the bytes of a path that may not appear verbatim in the request line.
+fn encode_path(path: &str) -> String {
+    let mut res = String::with_capacity(path.len());
+    for b in path.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@/".contains(&b) {
+            res.push(b as char);
+        } else {
+            res.push_str(&format!("%{b:02X}"));
+        }
+    }
+    res
+}
+
+///Creates the I/O error returned for a frame breaking the WebSocket protocol.
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("WebSocket: {reason}"))
+}
+
+///Creates the error returned for a failed opening handshake.
+fn handshake_error(reason: String) -> crate::types::error::Error {
+    crate::types::error::Error::WebSocketHandshakeError(reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use super::*;
+    use crate::{
+        options::ConnectOptions,
+        types::{payload::Payloads, ControlPacket, QOS},
+    };
+
+    ///Represents the server side of a WebSocket accepted by a test broker.
+    struct Peer {
+        stream: TcpStream,
+        bytes: VecDeque<u8>,
+    }
+
+    impl Peer {
+        ///Accepts a connection and reads its handshake request, answering it with the given status line.
+        fn accept(listener: &TcpListener, status: &str) -> (Self, String) {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut bytes = Vec::new();
+            while !bytes.ends_with(b"\r\n\r\n") {
+                let mut buf = [0_u8; 1];
+                stream.read_exact(&mut buf).unwrap();
+                bytes.extend(buf);
+            }
+            let request = String::from_utf8(bytes).unwrap();
+            let key = request
+                .split("\r\n")
+                .find_map(|line| line.strip_prefix("Sec-WebSocket-Key: "))
+                .unwrap();
+            let accept = STANDARD.encode(Sha1::digest(format!("{key}{ACCEPT_GUID}")));
+            write!(
+                stream,
+                "{status}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {accept}\r\nSec-WebSocket-Protocol: mqtt\r\n\r\n"
+            )
+            .unwrap();
+            let peer = Self {
+                stream,
+                bytes: VecDeque::new(),
+            };
+            (peer, request)
+        }
+        fn read_frame(&mut self) -> (u8, Vec<u8>) {
+            let header = self.read_bytes(6);
+            assert_eq!(header[1] & 0x80, 0x80, "client frames must be masked");
+            let len = (header[1] & 0x7F) as usize;
+            assert!(len < 126);
+            let mut payload = self.read_bytes(len);
+            payload.iter_mut().enumerate().for_each(|(i, b)| *b ^= header[2 + i % 4]);
+            (header[0] & 0x0F, payload)
+        }
+        fn read_bytes(&mut self, n: usize) -> Vec<u8> {
+            while self.bytes.len() < n {
+                let mut buf = [0_u8; 256];
+                let read = self.stream.read(&mut buf).unwrap();
+                assert!(read > 0);
+                self.bytes.extend(&buf[..read]);
+            }
+            self.bytes.drain(..n).collect()
+        }
+        fn write_frame(&mut self, first: u8, payload: &[u8]) {
+            let mut frame = vec![first, payload.len() as u8];
+            frame.extend(payload);
+            self.stream.write_all(&frame).unwrap();
+        }
+    }
+
+    #[test]
+    fn parse_frame_test() {
+        let mut bytes: VecDeque<u8> = [0x82, 0x02, 0x20].into();
+        assert_eq!(parse_frame(&mut bytes, DEFAULT_MAX_FRAME_SIZE).unwrap(), None);
+        assert_eq!(bytes.len(), 3);
+        bytes.extend([0x02, 0x89, 0x01, 0x69]);
+        assert_eq!(parse_frame(&mut bytes, DEFAULT_MAX_FRAME_SIZE).unwrap(), Some((OPCODE_BINARY, vec![0x20, 0x02])));
+        assert_eq!(parse_frame(&mut bytes, DEFAULT_MAX_FRAME_SIZE).unwrap(), Some((OPCODE_PING, vec![0x69])));
+        assert!(bytes.is_empty());
+        let mut bytes: VecDeque<u8> = [0x82, 126, 0x01, 0x00].into();
+        bytes.extend([7; 255]);
+        assert_eq!(parse_frame(&mut bytes, DEFAULT_MAX_FRAME_SIZE).unwrap(), None);
+        bytes.push_back(7);
+        assert_eq!(parse_frame(&mut bytes, DEFAULT_MAX_FRAME_SIZE).unwrap(), Some((OPCODE_BINARY, vec![7; 256])));
+        let mut bytes: VecDeque<u8> = [0xC2, 0x00].into();
+        assert!(parse_frame(&mut bytes, DEFAULT_MAX_FRAME_SIZE).is_err());
+    }
+    #[test]
+    fn parse_frame_test1() {
+        let mut bytes: VecDeque<u8> = [0x82, 127, 0x80, 0, 0, 0, 0, 0, 0, 0].into();
+        assert_eq!(
+            parse_frame(&mut bytes, usize::MAX).unwrap_err().to_string(),
+            "WebSocket: most significant bit of the frame length set"
+        );
+        let mut bytes: VecDeque<u8> = [0x82, 127, 0x7F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF].into();
+        assert_eq!(parse_frame(&mut bytes, usize::MAX).unwrap(), None);
+        assert_eq!(
+            parse_frame(&mut bytes, DEFAULT_MAX_FRAME_SIZE).unwrap_err().to_string(),
+            "WebSocket: frame too long"
+        );
+        let mut bytes: VecDeque<u8> = [0x82, 0x05, 1, 2, 3, 4, 5].into();
+        assert_eq!(parse_frame(&mut bytes, 4).unwrap_err().to_string(), "WebSocket: frame too long");
+        assert_eq!(parse_frame(&mut bytes, 5).unwrap(), Some((OPCODE_BINARY, vec![1, 2, 3, 4, 5])));
+    }
+    #[test]
+    fn parse_frame_test2() {
+        let mut bytes: VecDeque<u8> = [0x82, 0x81, 1, 2, 3, 4, 0x20].into();
+        assert_eq!(
+            parse_frame(&mut bytes, DEFAULT_MAX_FRAME_SIZE).unwrap_err().to_string(),
+            "WebSocket: masked frame from server"
+        );
+        let mut bytes: VecDeque<u8> = [0x09, 0x00].into();
+        assert_eq!(
+            parse_frame(&mut bytes, DEFAULT_MAX_FRAME_SIZE).unwrap_err().to_string(),
+            "WebSocket: invalid control frame"
+        );
+        let mut bytes: VecDeque<u8> = [0x89, 126, 0x00, 0x7E].into();
+        assert_eq!(
+            parse_frame(&mut bytes, DEFAULT_MAX_FRAME_SIZE).unwrap_err().to_string(),
+            "WebSocket: invalid control frame"
+        );
+        let mut bytes: VecDeque<u8> = [0x88, 125].into();
+        bytes.extend([0; 125]);
+        assert_eq!(parse_frame(&mut bytes, DEFAULT_MAX_FRAME_SIZE).unwrap(), Some((OPCODE_CLOSE, vec![0; 125])));
+    }
+    #[test]
+    fn websocket_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (mut peer, request) = Peer::accept(&listener, "HTTP/1.1 101 Switching Protocols");
+            let (opcode, connect) = peer.read_frame();
+            assert_eq!(opcode, OPCODE_BINARY);
+            peer.write_frame(OPCODE_BINARY, &[0x20, 0x02]);
+            peer.write_frame(0x80 | OPCODE_PING, b"hi");
+            peer.write_frame(0x80 | OPCODE_CONTINUATION, &[0x00, 0x00]);
+            let pong = peer.read_frame();
+            let (_, publish) = peer.read_frame();
+            (request, connect, pong, publish)
+        });
+        let client = ConnectOptions::new(&format!("ws://127.0.0.1:{port}/mqtt?client_id=id"))
+            .unwrap()
+            .websocket(WebSocketOptions::new().header("Authorization", "Bearer token"))
+            .connect()
+            .unwrap();
+        client.publish("a", "framed", QOS::Zero, false).unwrap();
+        let (request, connect, pong, publish) = handle.join().unwrap();
+        assert!(request.starts_with("GET /mqtt HTTP/1.1\r\n"));
+        assert!(request.contains("\r\nSec-WebSocket-Protocol: mqtt\r\n"));
+        assert!(request.contains("\r\nAuthorization: Bearer token\r\n"));
+        let connect = ControlPacket::from_bytes(&mut connect.into()).unwrap().unwrap();
+        assert_eq!(connect.header().fixed(), crate::types::header::FixedHeader::Connect);
+        assert_eq!(pong, (OPCODE_PONG, b"hi".to_vec()));
+        let publish = ControlPacket::from_bytes(&mut publish.into()).unwrap().unwrap();
+        assert_eq!(publish.payload().content, Some(Payloads::Publish(b"framed".to_vec())));
+    }
+    #[test]
+    fn websocket_test1() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            Peer::accept(&listener, "HTTP/1.1 404 Not Found");
+        });
+        let res = ConnectOptions::new(&format!("ws://127.0.0.1:{port}?client_id=id"))
+            .unwrap()
+            .connect();
+        handle.join().unwrap();
+        assert_eq!(
+            res.err(),
+            Some(crate::types::error::Error::WebSocketHandshakeError(
+                "unexpected status `HTTP/1.1 404 Not Found`".to_owned()
+            ))
+        );
+    }
+    #[test]
+    fn websocket_test2() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let res = ConnectOptions::new(&format!("ws://127.0.0.1:{port}/x%0d%0aEvil:%201?client_id=id"))
+            .unwrap()
+            .connect();
+        assert_eq!(
+            res.err(),
+            Some(crate::types::error::Error::WebSocketHandshakeError("control character in path".to_owned()))
+        );
+        let res = ConnectOptions::new(&format!("ws://127.0.0.1:{port}/mqtt?client_id=id"))
+            .unwrap()
+            .websocket(WebSocketOptions::new().header("Origin", "a\r\nEvil: 1"))
+            .connect();
+        assert_eq!(
+            res.err(),
+            Some(crate::types::error::Error::WebSocketHandshakeError("control character in header `Origin`".to_owned()))
+        );
+        let res = ConnectOptions::new(&format!("ws://127.0.0.1:{port}/mqtt?client_id=id"))
+            .unwrap()
+            .websocket(WebSocketOptions::new().header("Evil:\r\n", "1"))
+            .connect();
+        assert_eq!(
+            res.err(),
+            Some(crate::types::error::Error::WebSocketHandshakeError("invalid header name `Evil:\\r\\n`".to_owned()))
+        );
+    }
+    #[test]
+    fn encode_path_test() {
+        assert_eq!(encode_path("/mqtt"), "/mqtt");
+        assert_eq!(encode_path("/a b/100%/é"), "/a%20b/100%25/%C3%A9");
+    }
+}